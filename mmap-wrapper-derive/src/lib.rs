@@ -0,0 +1,173 @@
+//! Derive macro for `mmap_wrapper::MmapSafe`.
+//!
+//! Use it through the `derive` feature of `mmap-wrapper` rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Type};
+
+/// Implements `MmapSafe` for a struct after checking, at compile time, that:
+///
+/// - the struct is `#[repr(C)]` or `#[repr(transparent)]`
+/// - no field is a reference, raw pointer or function pointer
+/// - every field type implements `MmapSafe` itself
+/// - the struct has no padding, its size is the sum of its field sizes
+///
+/// Padding bytes are left uninitialized by writes of the whole struct, reading them back
+/// through a view of the mapping as plain bytes would be undefined behavior.
+/// Add explicit `_pad` fields instead, like `bytemuck::Pod` asks for.
+///
+/// Every type parameter of the struct gets an `MmapSafe` bound. Padding depends on the generic
+/// parameters, so generic structs are limited to a single field without `align(N)`.
+/// `LAYOUT_HASH` is derived from the size and alignment of the struct and the offset and
+/// hash of each field.
+#[proc_macro_derive(MmapSafe)]
+pub fn derive_mmap_safe(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match &input.data {
+        Data::Struct(s) => &s.fields,
+        Data::Enum(e) => return Err(Error::new_spanned(
+            e.enum_token,
+            "MmapSafe cannot be derived for enums, not every bit pattern is a valid discriminant",
+        )),
+        Data::Union(u) => {
+            return Err(Error::new_spanned(
+                u.union_token,
+                "MmapSafe cannot be derived for unions",
+            ))
+        }
+    };
+
+    let aligned = check_repr(&input)?;
+
+    let field_tys: Vec<&Type> = fields.iter().map(|f| &f.ty).collect();
+    let field_names: Vec<TokenStream2> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| match &f.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            }
+        })
+        .collect();
+
+    for ty in &field_tys {
+        check_field_ty(ty)?;
+    }
+
+    let generic = !input.generics.params.is_empty();
+    if generic && (field_tys.len() > 1 || aligned) {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "MmapSafe cannot check generic structs with several fields or align(N) for padding, \
+             implement it by hand",
+        ));
+    }
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::mmap_wrapper::MmapSafe));
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    // a single field without align(N) can't be padded, whatever the type parameters are
+    let padding_check = (!generic).then(|| {
+        let message = format!("`{name}` has padding bytes, add explicit fields to fill them");
+        quote! {
+            const _: () = assert!(
+                ::core::mem::size_of::<#name>() == 0 #( + ::core::mem::size_of::<#field_tys>() )*,
+                #message
+            );
+        }
+    });
+
+    Ok(quote! {
+        const _: () = {
+            fn assert_mmap_safe<T: ::mmap_wrapper::MmapSafe>() {}
+
+            #[allow(dead_code)]
+            fn assert_fields_mmap_safe #impl_generics () #where_clause {
+                #( assert_mmap_safe::<#field_tys>(); )*
+            }
+        };
+
+        #padding_check
+
+        unsafe impl #impl_generics ::mmap_wrapper::MmapSafe for #name #ty_generics #where_clause {
+            const LAYOUT_HASH: u64 = ::mmap_wrapper::LayoutHasher::new()
+                .write_u64(::core::mem::size_of::<Self>() as u64)
+                .write_u64(::core::mem::align_of::<Self>() as u64)
+                #(
+                    .write_u64(::core::mem::offset_of!(Self, #field_names) as u64)
+                    .write_u64(<#field_tys as ::mmap_wrapper::MmapSafe>::LAYOUT_HASH)
+                )*
+                .finish();
+        }
+    })
+}
+
+/// Checks for a stable layout, returning whether the struct also asks for a bigger alignment.
+fn check_repr(input: &DeriveInput) -> Result<bool, Error> {
+    let mut stable = false;
+    let mut aligned = false;
+
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("C") || meta.path.is_ident("transparent") {
+                stable = true;
+            } else if meta.input.peek(syn::token::Paren) {
+                // align(N) / packed(N)
+                aligned |= meta.path.is_ident("align");
+                let content;
+                syn::parenthesized!(content in meta.input);
+                content.parse::<TokenStream2>()?;
+            }
+            Ok(())
+        })?;
+    }
+
+    if !stable {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "MmapSafe requires #[repr(C)] or #[repr(transparent)]",
+        ));
+    }
+
+    Ok(aligned)
+}
+
+fn check_field_ty(ty: &Type) -> Result<(), Error> {
+    match ty {
+        Type::Reference(_) => Err(Error::new_spanned(
+            ty,
+            "references cannot be stored in a mapped file",
+        )),
+        Type::Ptr(_) => Err(Error::new_spanned(
+            ty,
+            "pointers cannot be stored in a mapped file",
+        )),
+        Type::BareFn(_) => Err(Error::new_spanned(
+            ty,
+            "function pointers cannot be stored in a mapped file",
+        )),
+        Type::Array(a) => check_field_ty(&a.elem),
+        Type::Group(g) => check_field_ty(&g.elem),
+        Type::Paren(p) => check_field_ty(&p.elem),
+        Type::Tuple(t) if !t.elems.is_empty() => Err(Error::new_spanned(
+            ty,
+            "tuples have no stable layout, use a #[repr(C)] struct instead",
+        )),
+        _ => Ok(()),
+    }
+}
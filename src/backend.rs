@@ -0,0 +1,116 @@
+use core::fmt::{Debug, Display};
+
+use crate::{HeaderError, LayoutError, OpenOptions};
+
+/// A memory mapping that [`MmapWrapper`] and [`MmapMutWrapper`] can be built on.
+///
+/// Each value owns one mapping and unmaps it when dropped, the wrappers take
+/// care of sharing it between clones.
+///
+/// Library code that only needs to map files can be written once against this
+/// trait and used with `Memmap2Backend` (`std` feature) or `LibcBackend` (`no_std` feature).
+///
+/// # Safety
+///
+/// - [`MappingBackend::as_ptr`] must point to [`MappingBackend::len`] readable bytes that
+///   stay mapped at the same address until the backend is dropped or unmapped, even if the
+///   backend value itself is moved.
+/// - Those bytes must be writable if [`MappingBackend::writable`] returns `true`, which it
+///   must for mappings made with `write` set.
+///
+/// [`MmapWrapper`]: crate::MmapWrapper
+/// [`MmapMutWrapper`]: crate::MmapMutWrapper
+pub unsafe trait MappingBackend: Sized {
+    /// How files are named, `Path` on std and `CStr` on no_std.
+    type Path: ?Sized + AsRef<Self::Path>;
+    /// Error returned by every fallible operation.
+    type Error: From<LayoutError> + From<HeaderError> + Debug + Display;
+
+    /// Maps `len` bytes of the file at `path` starting at [`OpenOptions::offset`], read-write
+    /// if `write` is set, or everything past the offset if `len` is `None`.
+    ///
+    /// The file is created or resized to `offset + len` according to `options`,
+    /// mapping fails if it ends up shorter than that.
+    /// An empty file maps to an empty region.
+    fn map(
+        path: &Self::Path,
+        options: &OpenOptions,
+        len: Option<usize>,
+        write: bool,
+    ) -> Result<Self, Self::Error>;
+
+    /// Replaces the file at `path` with a new one of `len` bytes written by `fill`.
+    ///
+    /// The new file is created next to the old one (`<path>.tmp`) with the flags of `options`,
+    /// mapped read-write for `fill`, flushed, given the permissions and owner of the old file and
+    /// renamed over `path`, so a crash leaves either the old file or the complete new one behind.
+    /// Fails if the owner can't be copied, like when someone else's file is replaced without
+    /// privileges.
+    fn replace(
+        path: &Self::Path,
+        options: &OpenOptions,
+        len: usize,
+        fill: impl FnOnce(&Self) -> Result<(), Self::Error>,
+    ) -> Result<(), Self::Error>;
+
+    /// Maps `len` zeroed bytes of private anonymous memory, read-write.
+    fn map_anon(len: usize) -> Result<Self, Self::Error>;
+
+    /// Unmaps the region, reporting errors that dropping it would ignore.
+    fn unmap(self) -> Result<(), Self::Error>;
+
+    /// Writes modified pages back to the file, blocking until done.
+    fn flush(&self) -> Result<(), Self::Error>;
+
+    /// Whether the mapped region can be written to.
+    fn writable(&self) -> bool;
+
+    /// Length of the mapped region in bytes.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if the mapped region is zero bytes long.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Start of the mapped region.
+    fn as_ptr(&self) -> *mut u8;
+}
+
+/// A [`MappingBackend`] that can keep a file open to resize it and its mapping later.
+///
+/// # Safety
+///
+/// Mappings returned by [`ResizableBackend::map_file`] and [`ResizableBackend::remap`]
+/// must uphold the same guarantees as [`MappingBackend::map`] with `write` set.
+pub unsafe trait ResizableBackend: MappingBackend {
+    /// An open file, closed when dropped.
+    type File;
+
+    /// Opens the file at `path` according to `options` without resizing it.
+    fn open(
+        path: &Self::Path,
+        options: &OpenOptions,
+        write: bool,
+    ) -> Result<Self::File, Self::Error>;
+
+    /// Current length of `file` in bytes.
+    fn file_len(file: &Self::File) -> Result<usize, Self::Error>;
+
+    /// Resizes `file` to `len` bytes, new bytes read as zero.
+    fn set_file_len(file: &Self::File, len: usize) -> Result<(), Self::Error>;
+
+    /// Keeps every other open of the file from taking the lock until `file` is closed,
+    /// failing right away if one already holds it (`flock` with `LOCK_EX | LOCK_NB`).
+    fn try_lock(file: &Self::File) -> Result<(), Self::Error>;
+
+    /// Maps the first `len` bytes of `file` read-write.
+    fn map_file(file: &Self::File, len: usize) -> Result<Self, Self::Error>;
+
+    /// Replaces a mapping made by [`ResizableBackend::map_file`] with one of the first `len`
+    /// bytes of `file`, possibly at a new address.
+    fn remap(&mut self, file: &Self::File, len: usize) -> Result<(), Self::Error> {
+        *self = Self::map_file(file, len)?;
+        Ok(())
+    }
+}
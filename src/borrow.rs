@@ -0,0 +1,133 @@
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicIsize, Ordering};
+
+const WRITING: isize = -1;
+
+/// Tracks the guards handed out by every clone of a mutable wrapper,
+/// like a `RefCell` that can be shared between threads.
+#[derive(Debug)]
+pub(crate) struct BorrowFlag(AtomicIsize);
+
+impl BorrowFlag {
+    pub(crate) const fn new() -> BorrowFlag {
+        BorrowFlag(AtomicIsize::new(0))
+    }
+
+    fn try_read(&self) -> Result<(), BorrowError> {
+        let mut cur = self.0.load(Ordering::Relaxed);
+        loop {
+            if cur == WRITING || cur == isize::MAX {
+                return Err(BorrowError);
+            }
+
+            match self
+                .0
+                .compare_exchange_weak(cur, cur + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return Ok(()),
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    fn try_write(&self) -> Result<(), BorrowError> {
+        self.0
+            .compare_exchange(0, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .map(|_| ())
+            .map_err(|_| BorrowError)
+    }
+}
+
+/// Returned when a guard conflicts with one already held by a clone of the same wrapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowError;
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("mapping is already borrowed mutably or immutably")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BorrowError {}
+
+/// Shared access to a mapped `T`, released when dropped.
+///
+/// Only wrappers of writable mappings hand these out, so anything that writes to the mapping
+/// through a shared reference, like locking a [`MmapMutex`] or storing into a [`MmapSeqLock`],
+/// is offered on `MmapRef`s of the type rather than on the type itself. Read-only mappings
+/// would fault on the write.
+///
+/// [`MmapMutex`]: crate::MmapMutex
+/// [`MmapSeqLock`]: crate::MmapSeqLock
+pub struct MmapRef<'a, T: ?Sized> {
+    value: &'a T,
+    flag: &'a BorrowFlag,
+}
+
+impl<'a, T: ?Sized> MmapRef<'a, T> {
+    /// # Safety
+    /// `value` must be valid for `'a` and only be handed out while `flag` allows it.
+    pub(crate) unsafe fn new(value: *const T, flag: &'a BorrowFlag) -> Result<Self, BorrowError> {
+        flag.try_read()?;
+
+        Ok(MmapRef {
+            value: unsafe { &*value },
+            flag,
+        })
+    }
+}
+
+impl<T: ?Sized> Deref for MmapRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for MmapRef<'_, T> {
+    fn drop(&mut self) {
+        self.flag.0.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Exclusive access to a mapped `T`, released when dropped.
+pub struct MmapRefMut<'a, T: ?Sized> {
+    value: &'a mut T,
+    flag: &'a BorrowFlag,
+}
+
+impl<'a, T: ?Sized> MmapRefMut<'a, T> {
+    /// # Safety
+    /// `value` must be valid for `'a` and only be handed out while `flag` allows it.
+    pub(crate) unsafe fn new(value: *mut T, flag: &'a BorrowFlag) -> Result<Self, BorrowError> {
+        flag.try_write()?;
+
+        Ok(MmapRefMut {
+            value: unsafe { &mut *value },
+            flag,
+        })
+    }
+}
+
+impl<T: ?Sized> Deref for MmapRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> DerefMut for MmapRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for MmapRefMut<'_, T> {
+    fn drop(&mut self) {
+        self.flag.0.store(0, Ordering::Release);
+    }
+}
@@ -0,0 +1,92 @@
+use core::fmt;
+use core::mem::{align_of, size_of};
+
+/// Returned when a mapped region cannot hold a value of type `T`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The region is shorter than `size_of::<T>()`.
+    TooSmall { len: usize, required: usize },
+    /// The region does not start on an `align_of::<T>()` boundary.
+    Misaligned { addr: usize, align: usize },
+    /// The region is not a whole number of `size_of::<T>()` records.
+    NotMultiple { len: usize, size: usize },
+    /// A [`RelPtr`](crate::RelPtr) that doesn't point anywhere was resolved.
+    Null,
+    /// A read-only mapping was handed to a wrapper that writes to it.
+    ReadOnly,
+    /// An offset or length does not fit in a `usize`.
+    Overflow,
+}
+
+impl LayoutError {
+    /// Checks that `len` bytes starting at `ptr` can be read as a `T`.
+    pub(crate) fn check<T>(ptr: *const u8, len: usize) -> Result<(), LayoutError> {
+        if len < size_of::<T>() {
+            return Err(LayoutError::TooSmall {
+                len,
+                required: size_of::<T>(),
+            });
+        }
+
+        if !(ptr as usize).is_multiple_of(align_of::<T>()) {
+            return Err(LayoutError::Misaligned {
+                addr: ptr as usize,
+                align: align_of::<T>(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `len` bytes starting at `ptr` can be read as a `[T]`, returning its length.
+    pub(crate) fn check_slice<T>(ptr: *const u8, len: usize) -> Result<usize, LayoutError> {
+        const { assert!(size_of::<T>() != 0, "cannot map slices of zero sized types") };
+
+        if !len.is_multiple_of(size_of::<T>()) {
+            return Err(LayoutError::NotMultiple {
+                len,
+                size: size_of::<T>(),
+            });
+        }
+
+        if !(ptr as usize).is_multiple_of(align_of::<T>()) {
+            return Err(LayoutError::Misaligned {
+                addr: ptr as usize,
+                align: align_of::<T>(),
+            });
+        }
+
+        Ok(len / size_of::<T>())
+    }
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::TooSmall { len, required } => write!(
+                f,
+                "mapping is {len} bytes but the inner type needs {required}"
+            ),
+            LayoutError::Misaligned { addr, align } => {
+                write!(f, "mapping at {addr:#x} is not aligned to {align} bytes")
+            }
+            LayoutError::NotMultiple { len, size } => write!(
+                f,
+                "mapping is {len} bytes which is not a multiple of the {size} byte record size"
+            ),
+            LayoutError::Null => write!(f, "relative pointer is null"),
+            LayoutError::ReadOnly => write!(f, "mapping is read-only but needs to be writable"),
+            LayoutError::Overflow => write!(f, "mapping offset or length overflows usize"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LayoutError {}
+
+#[cfg(feature = "std")]
+impl From<LayoutError> for std::io::Error {
+    fn from(e: LayoutError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
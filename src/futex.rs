@@ -0,0 +1,121 @@
+use core::ffi::{c_int, c_long};
+use core::ptr;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
+// shared futexes, `FUTEX_PRIVATE_FLAG` would only wake waiters in this process
+const FUTEX_WAIT: c_int = 0;
+const FUTEX_WAKE: c_int = 1;
+const ESRCH: c_int = 3;
+
+#[cfg(target_arch = "x86_64")]
+mod nr {
+    pub(super) const FUTEX: super::c_long = 202;
+    pub(super) const GETTID: super::c_long = 186;
+}
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+mod nr {
+    pub(super) const FUTEX: super::c_long = 240;
+    pub(super) const GETTID: super::c_long = 224;
+}
+#[cfg(any(
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "loongarch64"
+))]
+mod nr {
+    pub(super) const FUTEX: super::c_long = 98;
+    pub(super) const GETTID: super::c_long = 178;
+}
+#[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
+mod nr {
+    pub(super) const FUTEX: super::c_long = 221;
+    pub(super) const GETTID: super::c_long = 207;
+}
+#[cfg(target_arch = "s390x")]
+mod nr {
+    pub(super) const FUTEX: super::c_long = 238;
+    pub(super) const GETTID: super::c_long = 236;
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "loongarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "s390x"
+)))]
+compile_error!("the futex syscall number is not known for this architecture");
+
+#[repr(C)]
+struct Timespec {
+    tv_sec: c_long,
+    tv_nsec: c_long,
+}
+
+extern "C" {
+    fn syscall(num: c_long, ...) -> c_long;
+    fn kill(pid: c_int, sig: c_int) -> c_int;
+    #[cfg_attr(target_os = "linux", link_name = "__errno_location")]
+    #[cfg_attr(target_os = "android", link_name = "__errno")]
+    fn errno_location() -> *mut c_int;
+}
+
+/// Sleeps until woken if `futex` still holds `expected`, or until `timeout` passes.
+/// May also return spuriously.
+pub(crate) fn wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timeout = timeout.map(|t| Timespec {
+        tv_sec: t.as_secs().min(c_long::MAX as u64) as c_long,
+        tv_nsec: t.subsec_nanos() as c_long,
+    });
+    let timeout = timeout
+        .as_ref()
+        .map_or(ptr::null(), |t| t as *const Timespec);
+
+    unsafe {
+        syscall(
+            nr::FUTEX,
+            futex.as_ptr(),
+            FUTEX_WAIT,
+            expected,
+            timeout,
+            ptr::null::<u8>(),
+            0u32,
+        );
+    }
+}
+
+/// Sleeps for about `duration`, less if a signal comes in.
+pub(crate) fn sleep(duration: Duration) {
+    // nobody else can wake a futex on this stack
+    wait(&AtomicU32::new(0), 0, Some(duration));
+}
+
+/// Wakes up to `count` waiters on `futex` in any process.
+pub(crate) fn wake(futex: &AtomicU32, count: u32) {
+    unsafe {
+        syscall(
+            nr::FUTEX,
+            futex.as_ptr(),
+            FUTEX_WAKE,
+            count.min(i32::MAX as u32),
+            ptr::null::<u8>(),
+            ptr::null::<u8>(),
+            0u32,
+        );
+    }
+}
+
+/// Id of the calling thread, unique across processes in the same pid namespace.
+pub(crate) fn gettid() -> u32 {
+    unsafe { syscall(nr::GETTID) as u32 }
+}
+
+/// Returns `false` once the thread `tid` has exited.
+pub(crate) fn alive(tid: u32) -> bool {
+    unsafe { kill(tid as c_int, 0) == 0 || *errno_location() != ESRCH }
+}
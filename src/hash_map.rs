@@ -0,0 +1,607 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::ring::SlotLayout;
+use crate::{
+    DefaultBackend, LayoutHasher, MappingBackend, MmapHeaderSlice, MmapHeaderSliceReader, MmapSafe,
+    OpenOptions,
+};
+
+const EMPTY: u32 = 0;
+const FULL: u32 = 1;
+const TOMBSTONE: u32 = 2;
+
+// a write takes nanoseconds, a slot changing for longer was left behind by a dead writer
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const REPAIRED: &str = "the writer repairs torn slots when opening the map";
+
+#[repr(C)]
+pub(crate) struct MapHeader {
+    layout: SlotLayout,
+    // live entries
+    len: AtomicU64,
+    // live entries and tombstones, what the load factor is checked against
+    used: AtomicU64,
+    // set once a rehash has renamed a new file over this one
+    stale: AtomicU32,
+    _reserved: u32,
+}
+
+unsafe impl MmapSafe for MapHeader {}
+
+/// `seq` is odd while the writer is changing the slot, readers retry until
+/// they see the same even value before and after copying it out.
+///
+/// A writer that dies mid-write leaves `seq` odd, readers give up after [`WRITE_TIMEOUT`]
+/// and the next writer to open the map turns the slot into a tombstone.
+#[repr(C)]
+pub(crate) struct Slot<K, V> {
+    seq: AtomicU32,
+    state: AtomicU32,
+    key: UnsafeCell<K>,
+    value: UnsafeCell<V>,
+}
+
+unsafe impl<K: MmapSafe, V: MmapSafe> MmapSafe for Slot<K, V> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("Slot")
+        .write_u64(K::LAYOUT_HASH)
+        .write_u64(V::LAYOUT_HASH)
+        .finish();
+}
+
+impl<K: Copy, V: Copy> Slot<K, V> {
+    fn read(&self) -> Result<(u32, K, V), TornSlotError> {
+        let mut deadline = None;
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + WRITE_TIMEOUT);
+                if Instant::now() > deadline {
+                    return Err(TornSlotError);
+                }
+                std::thread::yield_now();
+                continue;
+            }
+
+            let state = self.state.load(Ordering::Relaxed);
+            let key = unsafe { ptr::read_volatile(self.key.get()) };
+            let value = unsafe { ptr::read_volatile(self.value.get()) };
+
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return Ok((state, key, value));
+            }
+        }
+    }
+
+    /// Finishes a write the last writer died in the middle of, dropping the entry.
+    /// Returns `true` if there was one.
+    fn repair(&self) -> bool {
+        let seq = self.seq.load(Ordering::Relaxed);
+        if seq.is_multiple_of(2) {
+            return false;
+        }
+
+        self.state.store(TOMBSTONE, Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Only called by the single writer.
+    fn write(&self, state: u32, key: K, value: V) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.state.store(state, Ordering::Relaxed);
+        unsafe {
+            ptr::write_volatile(self.key.get(), key);
+            ptr::write_volatile(self.value.get(), value);
+        }
+
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+/// Returned by [`MmapHashMapReader`] lookups that find a slot stuck halfway through a write.
+///
+/// The writer most likely died while changing it, opening the map for writing repairs the slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TornSlotError;
+
+impl fmt::Display for TornSlotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("hash map slot was left half written")
+    }
+}
+
+impl std::error::Error for TornSlotError {}
+
+/// 64-bit FNV-1a, the same in every process unlike `std`'s randomly seeded hashers.
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn start<K: Hash>(key: &K, capacity: usize) -> usize {
+    let mut hasher = FnvHasher(0xcbf2_9ce4_8422_2325);
+    key.hash(&mut hasher);
+    (hasher.finish() % capacity as u64) as usize
+}
+
+type Table<K, V, B> = MmapHeaderSlice<MapHeader, Slot<K, V>, B>;
+type ReadTable<K, V, B> = MmapHeaderSliceReader<MapHeader, Slot<K, V>, B>;
+
+/// Index of the slot holding `key`, and its value.
+fn find<K, V>(slots: &[Slot<K, V>], key: &K) -> Result<Option<(usize, V)>, TornSlotError>
+where
+    K: Copy + Eq + Hash,
+    V: Copy,
+{
+    let start = start(key, slots.len());
+
+    for i in (start..slots.len()).chain(0..start) {
+        match slots[i].read()? {
+            (EMPTY, _, _) => return Ok(None),
+            (FULL, k, v) if k == *key => return Ok(Some((i, v))),
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+/// Index of the slot holding `key`, and its value, for the writer that never leaves
+/// a slot half written.
+fn find_own<K, V>(slots: &[Slot<K, V>], key: &K) -> Option<(usize, V)>
+where
+    K: Copy + Eq + Hash,
+    V: Copy,
+{
+    find(slots, key).expect(REPAIRED)
+}
+
+/// A persistent hash map from `K` to `V` stored in a memory-mapped file, using linear probing.
+///
+/// Removed entries leave tombstones behind. Once live entries and tombstones fill three quarters
+/// of the slots the map is rehashed into a new file next to the old one (`<path>.rehash`),
+/// which is then renamed over it. Needs the `std` feature.
+///
+/// There must be only one writer, while any number of [`MmapHashMapReader`]s in this or other
+/// processes look keys up without write access. Each slot is guarded by a sequence number so
+/// readers never see a half written entry.
+///
+/// [`OpenOptions::offset`] is ignored, the map always starts at the beginning of the file.
+///
+/// # Example
+/// ```rust
+/// use mmap_wrapper::{MmapHashMap, MmapHashMapReader};
+///
+/// let mut map = MmapHashMap::<u64, f64>::create("/tmp/hash-map-mmap-test.bin", 16).unwrap();
+/// map.insert(7, 0.5).unwrap();
+/// map.insert(8, 1.5).unwrap();
+/// assert_eq!(map.remove(&8), Some(1.5));
+///
+/// let reader = MmapHashMapReader::<u64, f64>::open("/tmp/hash-map-mmap-test.bin").unwrap();
+/// assert_eq!(reader.get(&7), Ok(Some(0.5)));
+/// assert_eq!(reader.get(&8), Ok(None));
+/// ```
+pub struct MmapHashMap<K, V, B: MappingBackend = DefaultBackend> {
+    table: Table<K, V, B>,
+    path: PathBuf,
+    options: OpenOptions,
+}
+
+impl<K, V, B> MmapHashMap<K, V, B>
+where
+    K: MmapSafe + Copy + Eq + Hash,
+    V: MmapSafe + Copy,
+    B: MappingBackend<Path = Path>,
+    B::Error: From<io::Error>,
+{
+    /// Creates an empty map with `capacity` slots at `path`, replacing any existing file contents.
+    pub fn create(
+        path: impl AsRef<Path>,
+        capacity: usize,
+    ) -> Result<MmapHashMap<K, V, B>, B::Error> {
+        Self::create_with(
+            path,
+            OpenOptions::new().create(true).truncate(true),
+            capacity,
+        )
+    }
+
+    /// Creates an empty map with `capacity` slots at `path` opened according to `options`,
+    /// replacing any existing map.
+    pub fn create_with(
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+        capacity: usize,
+    ) -> Result<MmapHashMap<K, V, B>, B::Error> {
+        assert!(capacity > 0, "hash map capacity must not be zero");
+
+        let mut options = options.clone();
+        options.offset(0);
+
+        let mut table = Table::<K, V, B>::open_with_len(path.as_ref(), &options, capacity)?;
+        let (header, slots) = table.split_mut();
+        for slot in slots.iter_mut() {
+            slot.state.store(EMPTY, Ordering::Relaxed);
+        }
+        header.len.store(0, Ordering::Relaxed);
+        header.used.store(0, Ordering::Relaxed);
+        header.stale.store(0, Ordering::Relaxed);
+        header.layout.write::<Slot<K, V>>(capacity);
+
+        Ok(MmapHashMap {
+            table,
+            path: path.as_ref().to_path_buf(),
+            options,
+        })
+    }
+
+    /// Opens the map created at `path` for writing, checking that it maps `K` to `V`.
+    pub fn open(path: impl AsRef<Path>) -> Result<MmapHashMap<K, V, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Opens the map created at `path` for writing according to `options`,
+    /// checking that it maps `K` to `V`.
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapHashMap<K, V, B>, B::Error> {
+        let mut options = options.clone();
+        options.offset(0);
+
+        let table = Table::<K, V, B>::open_with(path.as_ref(), &options)?;
+        let (header, slots) = table.split();
+        header.layout.check::<Slot<K, V>>(slots.len())?;
+
+        // a writer that died mid-write left a slot behind, recount what is left
+        if slots.iter().filter(|slot| slot.repair()).count() > 0 {
+            let full = slots
+                .iter()
+                .filter(|s| s.state.load(Ordering::Relaxed) == FULL);
+            let used = slots
+                .iter()
+                .filter(|s| s.state.load(Ordering::Relaxed) != EMPTY);
+            header.len.store(full.count() as u64, Ordering::Relaxed);
+            header.used.store(used.count() as u64, Ordering::Relaxed);
+        }
+
+        Ok(MmapHashMap {
+            table,
+            path: path.as_ref().to_path_buf(),
+            options,
+        })
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.table.header().len.load(Ordering::Relaxed) as usize
+    }
+
+    /// Returns `true` if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.table.items().len()
+    }
+
+    /// The value stored for `key`.
+    pub fn get(&self, key: &K) -> Option<V> {
+        find_own(self.table.items(), key).map(|(_, v)| v)
+    }
+
+    /// Returns `true` if there is a value stored for `key`.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Stores `value` for `key`, returning the value it replaced.
+    ///
+    /// Rehashes first if the new entry would push the map over its load factor.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, B::Error> {
+        if let Some((i, old)) = find_own(self.table.items(), &key) {
+            self.table.items()[i].write(FULL, key, value);
+            return Ok(Some(old));
+        }
+
+        let header = self.table.header();
+        if (header.used.load(Ordering::Relaxed) as usize + 1) * 4 > self.capacity() * 3 {
+            // double unless most of the used slots are tombstones
+            let capacity = match (self.len() + 1) * 2 > self.capacity() {
+                true => self.capacity() * 2,
+                false => self.capacity(),
+            };
+            self.rehash(capacity)?;
+        }
+
+        self.insert_new(key, value);
+        Ok(None)
+    }
+
+    /// Stores an entry for a key that isn't in the map yet, reusing the first tombstone.
+    fn insert_new(&self, key: K, value: V) {
+        let (header, slots) = self.table.split();
+        let start = start(&key, slots.len());
+
+        for i in (start..slots.len()).chain(0..start) {
+            let state = slots[i].state.load(Ordering::Relaxed);
+            if state != FULL {
+                slots[i].write(FULL, key, value);
+                header.len.fetch_add(1, Ordering::Relaxed);
+                if state == EMPTY {
+                    header.used.fetch_add(1, Ordering::Relaxed);
+                }
+                return;
+            }
+        }
+
+        unreachable!("the load factor keeps a free slot");
+    }
+
+    /// Removes `key`, returning the value it had.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (i, old) = find_own(self.table.items(), key)?;
+
+        // leave the key behind so readers can't mistake the tombstone for the entry
+        self.table.items()[i].write(TOMBSTONE, *key, old);
+        self.table.header().len.fetch_sub(1, Ordering::Relaxed);
+
+        Some(old)
+    }
+
+    /// Moves every entry into a new file with `capacity` slots, dropping the tombstones,
+    /// and renames it over the old one.
+    ///
+    /// Readers keep seeing the old file until they [`MmapHashMapReader::refresh`].
+    ///
+    /// # Panics
+    /// Panics if `capacity` can't hold every entry.
+    pub fn rehash(&mut self, capacity: usize) -> Result<(), B::Error> {
+        assert!(capacity > self.len(), "hash map capacity too small");
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".rehash");
+        let tmp = PathBuf::from(tmp);
+
+        let mut options = self.options.clone();
+        options.create(true).create_new(false).truncate(true);
+
+        let new = Self::create_with(&tmp, &options, capacity)?;
+        for slot in self.table.items() {
+            if let (FULL, key, value) = slot.read().expect(REPAIRED) {
+                new.insert_new(key, value);
+            }
+        }
+        new.table.flush()?;
+
+        std::fs::rename(&tmp, &self.path)?;
+        self.table.header().stale.store(1, Ordering::Release);
+        self.table = new.table;
+
+        Ok(())
+    }
+
+    /// Writes modified pages back to the file, blocking until done.
+    pub fn flush(&self) -> Result<(), B::Error> {
+        self.table.flush()
+    }
+}
+
+/// Read-only access to a [`MmapHashMap`] that another handle or process is writing.
+///
+/// The file is mapped without write access. After the writer rehashes, this reader keeps
+/// looking at the old entries until it is refreshed.
+pub struct MmapHashMapReader<K, V, B: MappingBackend = DefaultBackend> {
+    table: ReadTable<K, V, B>,
+    path: PathBuf,
+    options: OpenOptions,
+}
+
+impl<K, V, B> MmapHashMapReader<K, V, B>
+where
+    K: MmapSafe + Copy + Eq + Hash,
+    V: MmapSafe + Copy,
+    B: MappingBackend<Path = Path>,
+{
+    /// Opens the map at `path` read-only, checking that it maps `K` to `V`.
+    pub fn open(path: impl AsRef<Path>) -> Result<MmapHashMapReader<K, V, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Opens the map at `path` read-only according to `options`, checking that it maps `K` to `V`.
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapHashMapReader<K, V, B>, B::Error> {
+        let mut options = options.clone();
+        options.offset(0);
+
+        Ok(MmapHashMapReader {
+            table: Self::open_table(path.as_ref(), &options)?,
+            path: path.as_ref().to_path_buf(),
+            options,
+        })
+    }
+
+    fn open_table(path: &Path, options: &OpenOptions) -> Result<ReadTable<K, V, B>, B::Error> {
+        let table = ReadTable::<K, V, B>::open_with(path, options)?;
+        table
+            .header()
+            .layout
+            .check::<Slot<K, V>>(table.items().len())?;
+
+        Ok(table)
+    }
+
+    /// Number of entries, may be out of date as soon as it returns.
+    pub fn len(&self) -> usize {
+        self.table.header().len.load(Ordering::Relaxed) as usize
+    }
+
+    /// Returns `true` if there are no entries, may be out of date as soon as it returns.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.table.items().len()
+    }
+
+    /// The value stored for `key`.
+    ///
+    /// Fails if the lookup runs into a slot the writer died in the middle of changing.
+    pub fn get(&self, key: &K) -> Result<Option<V>, TornSlotError> {
+        Ok(find(self.table.items(), key)?.map(|(_, v)| v))
+    }
+
+    /// Returns `true` if there is a value stored for `key`.
+    pub fn contains_key(&self, key: &K) -> Result<bool, TornSlotError> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Returns `true` if the writer has rehashed into a new file since this one was mapped.
+    pub fn is_stale(&self) -> bool {
+        self.table.header().stale.load(Ordering::Acquire) != 0
+    }
+
+    /// Maps the current file if the writer has rehashed. Returns `true` if it did.
+    pub fn refresh(&mut self) -> Result<bool, B::Error> {
+        if !self.is_stale() {
+            return Ok(false);
+        }
+
+        self.table = Self::open_table(&self.path, &self.options)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::Ordering;
+
+    use super::find_own;
+    use crate::{HeaderError, MmapHashMap, MmapHashMapReader, TornSlotError};
+
+    #[test]
+    fn insert_remove_reopen() {
+        let path = "/tmp/mmap-wrapper-test-hash-map";
+        let _ = std::fs::remove_file(path);
+
+        let mut map = MmapHashMap::<u32, u64>::create(path, 8).unwrap();
+        assert_eq!(map.insert(1, 10).unwrap(), None);
+        assert_eq!(map.insert(9, 90).unwrap(), None);
+        assert_eq!(map.insert(1, 11).unwrap(), Some(10));
+        assert_eq!(map.remove(&1), Some(11));
+        assert_eq!(map.remove(&1), None);
+        // the tombstone is reused
+        map.insert(2, 20).unwrap();
+        drop(map);
+
+        let map = MmapHashMap::<u32, u64>::open(path).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.capacity(), 8);
+        assert_eq!(
+            (map.get(&9), map.get(&2), map.get(&1)),
+            (Some(90), Some(20), None)
+        );
+
+        // same slot size, different key type
+        let e = MmapHashMapReader::<u64, u64>::open(path).err().unwrap();
+        assert!(matches!(
+            *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+            HeaderError::LayoutHash { .. }
+        ));
+    }
+
+    #[test]
+    fn reader_follows_rehash() {
+        let path = "/tmp/mmap-wrapper-test-hash-map-rehash";
+        let _ = std::fs::remove_file(path);
+
+        let mut map = MmapHashMap::<u64, u64>::create(path, 4).unwrap();
+        map.insert(0, 0).unwrap();
+        let mut reader = MmapHashMapReader::<u64, u64>::open(path).unwrap();
+
+        for i in 1..100 {
+            map.insert(i, i * i).unwrap();
+        }
+        assert!(map.capacity() >= 128);
+
+        assert!(reader.is_stale());
+        assert_eq!(reader.get(&0), Ok(Some(0)));
+        assert!(reader.refresh().unwrap());
+        assert_eq!(reader.len(), 100);
+        assert_eq!(reader.get(&99), Ok(Some(99 * 99)));
+        assert!(!std::path::Path::new("/tmp/mmap-wrapper-test-hash-map-rehash.rehash").exists());
+    }
+
+    #[test]
+    fn dead_writer() {
+        let path = "/tmp/mmap-wrapper-test-hash-map-dead-writer";
+        let _ = std::fs::remove_file(path);
+
+        let mut map = MmapHashMap::<u32, u64>::create(path, 8).unwrap();
+        map.insert(1, 10).unwrap();
+        map.insert(2, 20).unwrap();
+
+        // the writer dies halfway through changing the entry for 1
+        let (i, _) = find_own(map.table.items(), &1).unwrap();
+        map.table.items()[i].seq.fetch_add(1, Ordering::Relaxed);
+        drop(map);
+
+        let reader = MmapHashMapReader::<u32, u64>::open(path).unwrap();
+        assert_eq!(reader.get(&1), Err(TornSlotError));
+
+        let map = MmapHashMap::<u32, u64>::open(path).unwrap();
+        assert_eq!((map.get(&1), map.get(&2), map.len()), (None, Some(20), 1));
+        assert_eq!(reader.get(&1), Ok(None));
+    }
+
+    #[test]
+    fn concurrent_reader() {
+        let path = "/tmp/mmap-wrapper-test-hash-map-concurrent";
+        let _ = std::fs::remove_file(path);
+
+        let mut map = MmapHashMap::<u64, [u64; 4]>::create(path, 4096).unwrap();
+        let reader = std::thread::spawn(move || {
+            let reader = MmapHashMapReader::<u64, [u64; 4]>::open(path).unwrap();
+            for _ in 0..100_000 {
+                if let Some(v) = reader.get(&7).unwrap() {
+                    assert!(v.iter().all(|&x| x == v[0]));
+                }
+            }
+        });
+
+        for i in 0..10_000 {
+            map.insert(7, [i; 4]).unwrap();
+            map.insert(i + 100, [i; 4]).unwrap();
+            map.remove(&(i + 100));
+        }
+        reader.join().unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use core::fmt;
+use core::mem::{align_of, size_of};
+
+use crate::{LayoutError, MappingBackend, MmapSafe, OpenOptions};
+
+/// Magic number at the start of every file written in header mode.
+pub const MAGIC: [u8; 8] = *b"MMAPWRAP";
+
+const LITTLE_ENDIAN: u8 = 1;
+const BIG_ENDIAN: u8 = 2;
+
+/// Describes the `T` stored after it, written at the start of files mapped in header mode.
+///
+/// See [`OpenOptions::header`](crate::OpenOptions::header).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FileHeader {
+    magic: [u8; 8],
+    version: u32,
+    data_offset: u32,
+    size: u64,
+    align: u32,
+    endian: u8,
+    pointer_width: u8,
+    _reserved: [u8; 2],
+    layout_hash: u64,
+}
+
+unsafe impl MmapSafe for FileHeader {}
+
+impl FileHeader {
+    /// Header describing a `T` with the user chosen schema `version`.
+    pub fn new<T: MmapSafe>(version: u32) -> FileHeader {
+        FileHeader {
+            magic: MAGIC,
+            version,
+            data_offset: Self::data_offset::<T>() as u32,
+            size: size_of::<T>() as u64,
+            align: align_of::<T>() as u32,
+            endian: if cfg!(target_endian = "little") {
+                LITTLE_ENDIAN
+            } else {
+                BIG_ENDIAN
+            },
+            pointer_width: size_of::<usize>() as u8,
+            _reserved: [0; 2],
+            layout_hash: T::LAYOUT_HASH,
+        }
+    }
+
+    /// Where `T` starts in the file, right after the header rounded up to `align_of::<T>()`.
+    pub fn data_offset<T>() -> usize {
+        size_of::<FileHeader>().next_multiple_of(align_of::<T>())
+    }
+
+    /// User chosen schema version.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// `size_of` the stored type.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// `align_of` the stored type.
+    pub fn align(&self) -> u32 {
+        self.align
+    }
+
+    /// [`MmapSafe::LAYOUT_HASH`] of the stored type.
+    pub fn layout_hash(&self) -> u64 {
+        self.layout_hash
+    }
+
+    /// `true` for the all zero header of a freshly created file.
+    pub(crate) fn is_blank(&self) -> bool {
+        self.magic == [0; 8]
+    }
+
+    /// Checks that this header was written for a `T` with schema `version` on a compatible machine.
+    pub fn validate<T: MmapSafe>(&self, version: u32) -> Result<(), HeaderError> {
+        let expected = FileHeader::new::<T>(version);
+
+        if self.magic != MAGIC {
+            return Err(HeaderError::Magic(self.magic));
+        }
+        if self.endian != expected.endian {
+            return Err(HeaderError::Endian);
+        }
+        if self.pointer_width != expected.pointer_width {
+            return Err(HeaderError::PointerWidth {
+                found: self.pointer_width,
+                expected: expected.pointer_width,
+            });
+        }
+        if self.version != version {
+            return Err(HeaderError::Version {
+                found: self.version,
+                expected: version,
+            });
+        }
+        if self.size != expected.size || self.align != expected.align {
+            return Err(HeaderError::Layout {
+                found: (self.size, self.align),
+                expected: (expected.size, expected.align),
+            });
+        }
+        if self.data_offset != expected.data_offset {
+            return Err(HeaderError::DataOffset {
+                found: self.data_offset,
+                expected: expected.data_offset,
+            });
+        }
+        if self.layout_hash != expected.layout_hash {
+            return Err(HeaderError::LayoutHash {
+                found: self.layout_hash,
+                expected: expected.layout_hash,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes or checks the header at the start of `backend`, returning where `T` starts.
+///
+/// Without a `version` there is no header and `T` starts at 0. A header is only written if
+/// [`probe`] found the file too short to hold one, anything else has to carry a valid header.
+pub(crate) fn init<T: MmapSafe, B: MappingBackend>(
+    backend: &B,
+    version: Option<u32>,
+    fresh: bool,
+) -> Result<usize, B::Error> {
+    let Some(version) = version else {
+        return Ok(0);
+    };
+
+    LayoutError::check::<FileHeader>(backend.as_ptr(), backend.len())?;
+    let ptr = backend.as_ptr().cast::<FileHeader>();
+
+    let header = unsafe { ptr.read() };
+    // another opener of the same new file may have written it already
+    if fresh && header.is_blank() {
+        unsafe { ptr.write(FileHeader::new::<T>(version)) };
+    } else {
+        header.validate::<T>(version)?;
+    }
+
+    Ok(FileHeader::data_offset::<T>())
+}
+
+/// Checks an existing header before the full length of `T` is mapped, so a file written
+/// for a smaller type fails with a [`HeaderError`] rather than [`LayoutError::TooSmall`].
+///
+/// Returns `true` if the file is new, too short to hold a header before it was opened,
+/// so `write` openers get to write one with [`init`]. Longer files never get a header
+/// written over their first bytes, even if those are zero.
+pub(crate) fn probe<T: MmapSafe, B: MappingBackend>(
+    path: &B::Path,
+    options: &OpenOptions,
+    write: bool,
+) -> Result<bool, B::Error> {
+    let Some(version) = options.header else {
+        return Ok(false);
+    };
+
+    // everything past the offset, creating the file if asked to but not resizing it
+    let mut probe = options.clone();
+    probe.truncate(false).grow(options.grow || options.truncate);
+
+    let existing = B::map(path, &probe, None, write)?;
+    if write && existing.len() < size_of::<FileHeader>() {
+        // make room for the header, even if the caller maps no more than the file holds
+        if probe.grow {
+            B::map(path, &probe, Some(size_of::<FileHeader>()), true)?;
+        }
+        return Ok(true);
+    }
+
+    LayoutError::check::<FileHeader>(existing.as_ptr(), existing.len())?;
+    let header = unsafe { existing.as_ptr().cast::<FileHeader>().read() };
+    header.validate::<T>(version)?;
+
+    Ok(false)
+}
+
+/// Returned when a file header does not match the type it is opened as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The file does not start with [`MAGIC`], it was not written in header mode.
+    Magic([u8; 8]),
+    /// The file was written on a machine with a different byte order.
+    Endian,
+    /// The file was written on a machine with a different pointer width.
+    PointerWidth { found: u8, expected: u8 },
+    /// The file was written with a different schema version.
+    Version { found: u32, expected: u32 },
+    /// The stored type has a different `(size, align)`.
+    Layout {
+        found: (u64, u32),
+        expected: (u64, u32),
+    },
+    /// The stored type starts at a different offset.
+    DataOffset { found: u32, expected: u32 },
+    /// The stored type has the same size but a different field layout.
+    LayoutHash { found: u64, expected: u64 },
+    /// The header records a different number of slots than the file holds.
+    Capacity { found: u64, expected: u64 },
+    /// A migration from `u32::MAX`, which has no next schema version.
+    VersionOverflow,
+    /// The stored read and write indices of a ring are further apart than it has slots.
+    Indices { head: u64, tail: u64 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Magic(found) => write!(
+                f,
+                "file has no mmap-wrapper header (found magic {found:02x?})"
+            ),
+            HeaderError::Endian => f.write_str("file was written with a different byte order"),
+            HeaderError::PointerWidth { found, expected } => write!(
+                f,
+                "file was written with {}-bit pointers but this machine has {}-bit pointers",
+                *found as u32 * 8,
+                *expected as u32 * 8
+            ),
+            HeaderError::Version { found, expected } => write!(
+                f,
+                "file has schema version {found} but version {expected} was expected"
+            ),
+            HeaderError::Layout { found, expected } => write!(
+                f,
+                "file stores a type of size {} and align {} but the type has size {} and align {}",
+                found.0, found.1, expected.0, expected.1
+            ),
+            HeaderError::DataOffset { found, expected } => write!(
+                f,
+                "file stores its data at offset {found} but offset {expected} was expected"
+            ),
+            HeaderError::LayoutHash { found, expected } => write!(
+                f,
+                "file layout hash {found:#018x} does not match the type's {expected:#018x}"
+            ),
+            HeaderError::Capacity { found, expected } => write!(
+                f,
+                "header records a capacity of {found} slots but the file holds {expected}"
+            ),
+            HeaderError::VersionOverflow => {
+                f.write_str("schema version u32::MAX cannot be migrated any further")
+            }
+            HeaderError::Indices { head, tail } => write!(
+                f,
+                "ring indices {head} and {tail} are further apart than the ring has slots"
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for HeaderError {}
+
+#[cfg(feature = "std")]
+impl From<HeaderError> for std::io::Error {
+    fn from(e: HeaderError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[cfg(all(test, feature = "std", feature = "derive"))]
+mod tests {
+    use crate::{FileHeader, HeaderError, MmapSafe, OpenOptions};
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct V1 {
+        a: u32,
+        _pad: u32,
+        b: u64,
+    }
+
+    // same size and alignment as V1, different fields
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct Swapped {
+        b: u64,
+        a: u32,
+        _pad: u32,
+    }
+
+    fn header_err(e: std::io::Error) -> HeaderError {
+        *e.into_inner().unwrap().downcast::<HeaderError>().unwrap()
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let path = "/tmp/mmap-wrapper-test-header-roundtrip";
+        let _ = std::fs::remove_file(path);
+
+        let mut rw = OpenOptions::new()
+            .create(true)
+            .grow(true)
+            .header(1)
+            .map_mut::<V1>(path)
+            .unwrap();
+        rw.write().b = 42;
+
+        let ro = OpenOptions::new().header(1).map::<V1>(path).unwrap();
+        assert_eq!(ro.read().b, 42);
+        assert_eq!(
+            std::fs::metadata(path).unwrap().len() as usize,
+            FileHeader::data_offset::<V1>() + core::mem::size_of::<V1>()
+        );
+    }
+
+    #[test]
+    fn header_mismatch() {
+        let path = "/tmp/mmap-wrapper-test-header-mismatch";
+        let _ = std::fs::remove_file(path);
+
+        let _ = OpenOptions::new()
+            .create(true)
+            .grow(true)
+            .header(1)
+            .map_mut::<V1>(path)
+            .unwrap();
+
+        let e = OpenOptions::new().header(2).map::<V1>(path).err().unwrap();
+        assert_eq!(
+            header_err(e),
+            HeaderError::Version {
+                found: 1,
+                expected: 2
+            }
+        );
+
+        let e = OpenOptions::new().header(1).map::<u64>(path).err().unwrap();
+        assert!(matches!(header_err(e), HeaderError::Layout { .. }));
+
+        let e = OpenOptions::new()
+            .header(1)
+            .map::<Swapped>(path)
+            .err()
+            .unwrap();
+        assert!(matches!(header_err(e), HeaderError::LayoutHash { .. }));
+    }
+
+    #[test]
+    fn headerless_file() {
+        let path = "/tmp/mmap-wrapper-test-headerless";
+        std::fs::write(path, [0xffu8; 64]).unwrap();
+
+        let e = OpenOptions::new()
+            .header(1)
+            .map_mut::<V1>(path)
+            .err()
+            .unwrap();
+        assert_eq!(header_err(e), HeaderError::Magic([0xff; 8]));
+        assert_eq!(
+            HeaderError::Magic([0xff; 8]).to_string(),
+            "file has no mmap-wrapper header (found magic [ff, ff, ff, ff, ff, ff, ff, ff])"
+        );
+
+        // zeroes at the start of an existing file are data, not room for a header
+        let mut legacy = [7u8; 128];
+        legacy[..8].fill(0);
+        std::fs::write(path, legacy).unwrap();
+
+        let e = OpenOptions::new()
+            .header(1)
+            .map_mut::<V1>(path)
+            .err()
+            .unwrap();
+        assert_eq!(header_err(e), HeaderError::Magic([0; 8]));
+        assert_eq!(std::fs::read(path).unwrap(), legacy);
+    }
+}
@@ -0,0 +1,377 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::slice;
+
+use crate::header::{self, FileHeader};
+use crate::{DefaultBackend, LayoutError, LayoutHasher, MappingBackend, MmapSafe, OpenOptions};
+
+/// What the [`FileHeader`] of a header slice describes: `H` aligned for both `H` and the
+/// records, so the records after it are aligned too, and a layout hash covering both.
+///
+/// Only used to describe the file, never read or written.
+#[repr(C)]
+struct Layout<H, T>(H, [T; 0]);
+
+unsafe impl<H: MmapSafe, T: MmapSafe> MmapSafe for Layout<H, T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("MmapHeaderSlice")
+        .write_u64(H::LAYOUT_HASH)
+        .write_u64(T::LAYOUT_HASH)
+        .finish();
+}
+
+/// A memory-mapped file holding a fixed `H` followed by an array of `T` records.
+///
+/// `H` sits at the start of the file (after the [`FileHeader`] in header mode) and the records
+/// start at the next offset aligned for `T`. The number of records comes from the file size.
+/// In header mode the stored layout hash covers both `H` and `T`.
+///
+/// The mapping is always read-write and owned by this value, use
+/// [`MmapHeaderSlice::split_mut`] to mutate the header and records at the same time.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapHeaderSlice, MmapSafe};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct Table {
+///    used: u32,
+/// }
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct Entry {
+///    key: u64,
+///    value: u64,
+/// }
+///
+/// # let _ = std::fs::remove_file("/tmp/table-mmap-test.bin");
+/// let mut table = MmapHeaderSlice::<Table, Entry>::create("/tmp/table-mmap-test.bin", 8).unwrap();
+///
+/// let (header, entries) = table.split_mut();
+/// entries[header.used as usize].key = 1;
+/// header.used += 1;
+///
+/// assert_eq!(table.header().used, 1);
+/// assert_eq!(table.items().len(), 8);
+/// # }
+/// ```
+pub struct MmapHeaderSlice<H, T, B: MappingBackend = DefaultBackend> {
+    map: B,
+    // where `H` starts, past the file header if there is one
+    offset: usize,
+    len: usize,
+    _inner: PhantomData<(H, T)>,
+}
+
+/// Where the records start relative to `H`.
+const fn items_offset<H, T>() -> usize {
+    size_of::<H>().next_multiple_of(align_of::<T>())
+}
+
+/// Checks that `backend` holds an `H` at `offset` followed by a whole number of records,
+/// returning how many.
+fn records<H, T, B: MappingBackend>(backend: &B, offset: usize) -> Result<usize, LayoutError> {
+    let base = backend.as_ptr().wrapping_add(offset);
+    let len = backend.len().saturating_sub(offset);
+    LayoutError::check::<H>(base, len)?;
+
+    let items = items_offset::<H, T>().min(len);
+    LayoutError::check_slice::<T>(base.wrapping_add(items), len - items)
+}
+
+impl<H: MmapSafe, T: MmapSafe, B: MappingBackend> MmapHeaderSlice<H, T, B> {
+    /// Wraps an existing writable mapping, checking that it holds an `H` followed by
+    /// a whole number of records.
+    ///
+    /// Fails with [`LayoutError::ReadOnly`] for a read-only mapping.
+    pub fn from_backend(backend: B) -> Result<MmapHeaderSlice<H, T, B>, B::Error> {
+        if !backend.writable() {
+            return Err(LayoutError::ReadOnly.into());
+        }
+        Self::from_parts(backend, 0)
+    }
+
+    fn from_parts(backend: B, offset: usize) -> Result<MmapHeaderSlice<H, T, B>, B::Error> {
+        let len = records::<H, T, B>(&backend, offset)?;
+
+        Ok(MmapHeaderSlice {
+            map: backend,
+            offset,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    /// Maps the file at `path`, creating it if necessary and resizing it to hold exactly `len` records.
+    pub fn create(
+        path: impl AsRef<B::Path>,
+        len: usize,
+    ) -> Result<MmapHeaderSlice<H, T, B>, B::Error> {
+        Self::open_with_len(path, OpenOptions::new().create(true).truncate(true), len)
+    }
+
+    /// Maps the whole existing file at `path` without resizing it.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapHeaderSlice<H, T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the whole file at `path`, opening it according to `options`.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapHeaderSlice<H, T, B>, B::Error> {
+        let fresh = header::probe::<Layout<H, T>, B>(path.as_ref(), options, true)?;
+        let backend = B::map(path.as_ref(), options, None, true)?;
+        let offset = header::init::<Layout<H, T>, B>(&backend, options.header, fresh)?;
+        Self::from_parts(backend, offset)
+    }
+
+    /// Maps an `H` and the first `len` records of the file at `path`,
+    /// creating or resizing it according to `options`.
+    pub fn open_with_len(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+        len: usize,
+    ) -> Result<MmapHeaderSlice<H, T, B>, B::Error> {
+        let offset = match options.header {
+            Some(_) => FileHeader::data_offset::<Layout<H, T>>(),
+            None => 0,
+        };
+        let map_len = len
+            .checked_mul(size_of::<T>())
+            .and_then(|n| n.checked_add(offset + items_offset::<H, T>()))
+            .ok_or(LayoutError::Overflow)?;
+
+        let fresh = header::probe::<Layout<H, T>, B>(path.as_ref(), options, true)?;
+        let backend = B::map(path.as_ref(), options, Some(map_len), true)?;
+        let offset = header::init::<Layout<H, T>, B>(&backend, options.header, fresh)?;
+        Self::from_parts(backend, offset)
+    }
+
+    fn header_ptr(&self) -> *mut H {
+        unsafe { self.map.as_ptr().add(self.offset).cast() }
+    }
+
+    fn items_ptr(&self) -> *mut T {
+        unsafe {
+            self.map
+                .as_ptr()
+                .add(self.offset + items_offset::<H, T>())
+                .cast()
+        }
+    }
+
+    /// The mapped header.
+    pub fn header(&self) -> &H {
+        unsafe { &*self.header_ptr() }
+    }
+
+    /// The mapped header, mutably.
+    pub fn header_mut(&mut self) -> &mut H {
+        unsafe { &mut *self.header_ptr() }
+    }
+
+    /// The mapped records.
+    pub fn items(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.items_ptr(), self.len) }
+    }
+
+    /// The mapped records, mutably.
+    pub fn items_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.items_ptr(), self.len) }
+    }
+
+    /// The header and records at the same time.
+    pub fn split(&self) -> (&H, &[T]) {
+        (self.header(), self.items())
+    }
+
+    /// The header and records mutably at the same time, they never overlap.
+    pub fn split_mut(&mut self) -> (&mut H, &mut [T]) {
+        unsafe {
+            (
+                &mut *self.header_ptr(),
+                slice::from_raw_parts_mut(self.items_ptr(), self.len),
+            )
+        }
+    }
+
+    /// Writes modified pages back to the file, blocking until done.
+    pub fn flush(&self) -> Result<(), B::Error> {
+        self.map.flush()
+    }
+}
+
+/// Read-only counterpart of [`MmapHeaderSlice`], the file is mapped without write access.
+pub struct MmapHeaderSliceReader<H, T, B: MappingBackend = DefaultBackend> {
+    map: B,
+    // where `H` starts, past the file header if there is one
+    offset: usize,
+    len: usize,
+    _inner: PhantomData<(H, T)>,
+}
+
+impl<H: MmapSafe, T: MmapSafe, B: MappingBackend> MmapHeaderSliceReader<H, T, B> {
+    /// Wraps an existing mapping, checking that it holds an `H` followed by
+    /// a whole number of records.
+    pub fn from_backend(backend: B) -> Result<MmapHeaderSliceReader<H, T, B>, B::Error> {
+        Self::from_parts(backend, 0)
+    }
+
+    fn from_parts(backend: B, offset: usize) -> Result<MmapHeaderSliceReader<H, T, B>, B::Error> {
+        let len = records::<H, T, B>(&backend, offset)?;
+
+        Ok(MmapHeaderSliceReader {
+            map: backend,
+            offset,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    /// Maps the whole existing file at `path` read-only.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapHeaderSliceReader<H, T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the whole file at `path` read-only, opening it according to `options`.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapHeaderSliceReader<H, T, B>, B::Error> {
+        header::probe::<Layout<H, T>, B>(path.as_ref(), options, false)?;
+        let backend = B::map(path.as_ref(), options, None, false)?;
+        let offset = header::init::<Layout<H, T>, B>(&backend, options.header, false)?;
+        Self::from_parts(backend, offset)
+    }
+
+    /// The mapped header.
+    pub fn header(&self) -> &H {
+        unsafe { &*self.map.as_ptr().add(self.offset).cast() }
+    }
+
+    /// The mapped records.
+    pub fn items(&self) -> &[T] {
+        unsafe {
+            let items = self.map.as_ptr().add(self.offset + items_offset::<H, T>());
+            slice::from_raw_parts(items.cast(), self.len)
+        }
+    }
+
+    /// The header and records at the same time.
+    pub fn split(&self) -> (&H, &[T]) {
+        (self.header(), self.items())
+    }
+}
+
+#[cfg(all(test, feature = "std", feature = "derive"))]
+mod tests {
+    use crate::{
+        HeaderError, LayoutError, MmapHeaderSlice, MmapHeaderSliceReader, MmapSafe, OpenOptions,
+    };
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct Count {
+        n: u8,
+    }
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct Entry {
+        value: u64,
+    }
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct Pair {
+        a: u64,
+        b: u64,
+    }
+
+    #[test]
+    fn header_then_items() {
+        let path = "/tmp/mmap-wrapper-test-header-slice";
+        let _ = std::fs::remove_file(path);
+
+        let mut hs = MmapHeaderSlice::<Count, Entry>::create(path, 4).unwrap();
+        let (count, entries) = hs.split_mut();
+        for e in entries.iter_mut() {
+            e.value = 7;
+            count.n += 1;
+        }
+        drop(hs);
+
+        // the single byte header is padded to the alignment of the entries
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 8 + 4 * 8);
+
+        let hs = MmapHeaderSliceReader::<Count, Entry>::open(path).unwrap();
+        assert_eq!(hs.header().n, 4);
+        assert_eq!(hs.items().iter().map(|e| e.value).sum::<u64>(), 28);
+    }
+
+    #[test]
+    fn header_slice_with_wide_records() {
+        let path = "/tmp/mmap-wrapper-test-header-slice-wide";
+        let _ = std::fs::remove_file(path);
+
+        // the file header is 40 bytes, the records need 16 byte alignment
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(true).header(1);
+        let mut hs = MmapHeaderSlice::<u8, u128>::open_with_len(path, &options, 3).unwrap();
+        *hs.header_mut() = 3;
+        hs.items_mut()[2] = u128::MAX;
+        drop(hs);
+
+        let hs = MmapHeaderSliceReader::<u8, u128>::open_with(path, OpenOptions::new().header(1))
+            .unwrap();
+        assert_eq!(*hs.header(), 3);
+        assert_eq!(hs.items(), [0, 0, u128::MAX]);
+    }
+
+    #[test]
+    fn header_slice_len_overflow() {
+        let path = "/tmp/mmap-wrapper-test-header-slice-overflow";
+        let _ = std::fs::remove_file(path);
+
+        let e = MmapHeaderSlice::<Count, Entry>::create(path, usize::MAX / 8)
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), LayoutError::Overflow.to_string());
+        assert!(!std::path::Path::new(path).exists());
+    }
+
+    #[test]
+    fn header_slice_with_file_header() {
+        let path = "/tmp/mmap-wrapper-test-header-slice-file-header";
+        let _ = std::fs::remove_file(path);
+
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(true).header(1);
+        MmapHeaderSlice::<Count, Entry>::open_with_len(path, &options, 2).unwrap();
+
+        let hs =
+            MmapHeaderSlice::<Count, Entry>::open_with(path, OpenOptions::new().header(1)).unwrap();
+        assert_eq!(hs.items().len(), 2);
+        drop(hs);
+
+        // the stored hash covers the records too, not just the header
+        let e = MmapHeaderSlice::<Count, Pair>::open_with(path, OpenOptions::new().header(1))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+            HeaderError::LayoutHash { .. }
+        ));
+
+        std::fs::write(path, [0u8; 12]).unwrap();
+        let e = MmapHeaderSlice::<Count, Entry>::open(path).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            LayoutError::NotMultiple { len: 4, size: 8 }.to_string()
+        );
+    }
+}
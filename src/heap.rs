@@ -0,0 +1,387 @@
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::slice;
+
+use crate::{DefaultBackend, HeaderError, LayoutError, OpenOptions, RelPtr, ResizableBackend};
+
+const HEAP_MAGIC: [u8; 8] = *b"MMAPHEAP";
+
+/// Blocks are `1 << class` bytes, from 32 bytes up to 16 TiB.
+const MIN_CLASS: u32 = 5;
+const CLASSES: usize = 40;
+
+const MIN_ALIGN: usize = 16;
+/// Offsets are only aligned relative to the page aligned start of the mapping.
+const MAX_ALIGN: usize = 4096;
+const MIN_CAP: usize = 4096;
+
+const ALLOCATED: u32 = 0xa110_ca7e;
+const FREE: u32 = 0xf4ee_b10c;
+
+/// Stored at the start of the file, followed by the blocks.
+#[repr(C)]
+struct HeapHeader {
+    magic: [u8; 8],
+    // end of the last block, everything past it has never been handed out
+    top: u64,
+    // first free block of every size class, 0 if there is none
+    free: [u64; CLASSES],
+}
+
+/// Stored at the start of every block.
+///
+/// The 8 bytes right before an allocation always hold the offset of its block,
+/// which is `next` itself unless the allocation needed more than 16 byte alignment.
+#[repr(C)]
+struct BlockHeader {
+    class: u32,
+    state: u32,
+    // next block in the same free list while free
+    next: u64,
+}
+
+const DATA_OFFSET: usize = size_of::<HeapHeader>().next_multiple_of(MIN_ALIGN);
+
+/// A persistent allocator managing the space inside one growable memory-mapped file.
+///
+/// Allocations are handed out as [`RelPtr`]s from the start of the file, resolve them
+/// against [`MmapHeap::as_bytes`]. Every allocation takes a block from a power of two
+/// size class, freed blocks go on a free list per class and new blocks are carved off
+/// the end of the file, which grows geometrically.
+///
+/// Opening the heap walks every block and rebuilds the free lists, so a crash in the middle
+/// of [`MmapHeap::alloc`] or [`MmapHeap::free`] at worst leaks the block being allocated.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use core::alloc::Layout;
+/// use mmap_wrapper::{MmapHeap, MmapSafe, RelPtr};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct Node {
+///    value: u64,
+///    next: RelPtr<Node>,
+/// }
+///
+/// let mut heap: MmapHeap = MmapHeap::create("/tmp/heap-mmap-test.bin").unwrap();
+/// let node = heap.alloc(Layout::new::<Node>()).unwrap().cast::<Node>();
+/// *node.resolve_mut(heap.as_bytes_mut()).unwrap() = Node { value: 1, next: RelPtr::null() };
+/// drop(heap);
+///
+/// let mut heap: MmapHeap = MmapHeap::open("/tmp/heap-mmap-test.bin").unwrap();
+/// assert_eq!(node.resolve(heap.as_bytes()).unwrap().value, 1);
+/// heap.free(node.cast());
+/// # }
+/// ```
+pub struct MmapHeap<B: ResizableBackend = DefaultBackend> {
+    file: B::File,
+    map: B,
+}
+
+impl<B: ResizableBackend> MmapHeap<B> {
+    /// Maps the heap at `path`, creating it if necessary and dropping any existing allocations.
+    pub fn create(path: impl AsRef<B::Path>) -> Result<MmapHeap<B>, B::Error> {
+        Self::open_with(path, OpenOptions::new().create(true).truncate(true))
+    }
+
+    /// Maps the existing heap at `path`, keeping its allocations.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapHeap<B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the heap at `path`, opening it according to `options`.
+    ///
+    /// An empty file becomes an empty heap and [`OpenOptions::truncate`] empties an existing one.
+    /// The heap always starts at the beginning of the file, [`OpenOptions::offset`] is ignored.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapHeap<B>, B::Error> {
+        let file = B::open(path.as_ref(), options, true)?;
+        let mut file_len = B::file_len(&file)?;
+        if options.truncate || file_len == 0 {
+            B::set_file_len(&file, 0)?;
+            B::set_file_len(&file, DATA_OFFSET)?;
+            file_len = DATA_OFFSET;
+        }
+
+        let map = B::map_file(&file, file_len)?;
+        LayoutError::check::<HeapHeader>(map.as_ptr(), map.len())?;
+
+        let mut heap = MmapHeap { file, map };
+        let header = heap.header();
+        if header.magic == [0; 8] && header.top == 0 {
+            header.magic = HEAP_MAGIC;
+            header.top = DATA_OFFSET as u64;
+        } else if header.magic != HEAP_MAGIC {
+            return Err(HeaderError::Magic(header.magic).into());
+        }
+        heap.recover();
+
+        Ok(heap)
+    }
+
+    fn header(&mut self) -> &mut HeapHeader {
+        unsafe { &mut *self.map.as_ptr().cast::<HeapHeader>() }
+    }
+
+    /// The block header at `offset`, the caller checks it is in bounds.
+    fn block(&mut self, offset: usize) -> &mut BlockHeader {
+        unsafe { &mut *self.map.as_ptr().add(offset).cast::<BlockHeader>() }
+    }
+
+    fn word(&mut self, offset: usize) -> &mut u64 {
+        unsafe { &mut *self.map.as_ptr().add(offset).cast::<u64>() }
+    }
+
+    /// Walks every block, dropping a torn one at the end and rebuilding the free lists.
+    fn recover(&mut self) {
+        let len = self.map.len();
+        let top = (self.header().top as usize).clamp(DATA_OFFSET, len);
+        self.header().free = [0; CLASSES];
+
+        let mut offset = DATA_OFFSET;
+        while offset + size_of::<BlockHeader>() <= top {
+            let BlockHeader { class, state, .. } = *self.block(offset);
+            if !(MIN_CLASS..MIN_CLASS + CLASSES as u32).contains(&class)
+                || (state != ALLOCATED && state != FREE)
+                || (top - offset) >> class == 0
+            {
+                break;
+            }
+
+            if state == FREE {
+                self.push(offset, class);
+            }
+            offset += 1 << class;
+        }
+
+        self.header().top = offset as u64;
+    }
+
+    fn push(&mut self, offset: usize, class: u32) {
+        let head = &mut self.header().free[(class - MIN_CLASS) as usize];
+        let next = *head;
+        *head = offset as u64;
+
+        let block = self.block(offset);
+        block.next = next;
+        block.state = FREE;
+    }
+
+    fn pop(&mut self, class: u32) -> Option<usize> {
+        let offset = self.header().free[(class - MIN_CLASS) as usize] as usize;
+        if offset == 0 {
+            return None;
+        }
+
+        let next = self.block(offset).next;
+        self.header().free[(class - MIN_CLASS) as usize] = next;
+        Some(offset)
+    }
+
+    /// Makes room for `additional` more bytes of blocks, growing the file geometrically.
+    fn reserve(&mut self, additional: usize) -> Result<(), B::Error> {
+        let required = self.header().top as usize + additional;
+        if required <= self.map.len() {
+            return Ok(());
+        }
+
+        let file_len = required.max(self.map.len() * 2).max(MIN_CAP);
+        B::set_file_len(&self.file, file_len)?;
+        self.map.remap(&self.file, file_len)?;
+
+        Ok(())
+    }
+
+    /// Allocates memory for `layout`, growing the file if no freed block fits.
+    ///
+    /// # Panics
+    /// Panics if `layout` needs more than 4096 byte alignment or more than 16 TiB.
+    pub fn alloc(&mut self, layout: Layout) -> Result<RelPtr<u8>, B::Error> {
+        let align = layout.align().max(MIN_ALIGN);
+        assert!(align <= MAX_ALIGN, "heap alignment above {MAX_ALIGN} bytes");
+
+        // the allocation starts at most `align` bytes into the block
+        let needed = layout
+            .size()
+            .checked_add(align)
+            .expect("heap allocation too large");
+        let class = needed
+            .max(1 << MIN_CLASS)
+            .next_power_of_two()
+            .trailing_zeros();
+        assert!(
+            class < MIN_CLASS + CLASSES as u32,
+            "heap allocation too large"
+        );
+
+        let offset = match self.pop(class) {
+            Some(offset) => offset,
+            None => {
+                self.reserve(1 << class)?;
+                self.header().top as usize
+            }
+        };
+
+        let block = self.block(offset);
+        block.class = class;
+        block.state = ALLOCATED;
+
+        let ptr = (offset + size_of::<BlockHeader>()).next_multiple_of(align);
+        *self.word(ptr - 8) = offset as u64;
+
+        // only count a new block once its header is written
+        let top = &mut self.header().top;
+        *top = (*top).max((offset + (1 << class)) as u64);
+
+        Ok(RelPtr::new(ptr))
+    }
+
+    /// Returns the memory at `ptr` to its size class' free list.
+    ///
+    /// # Panics
+    /// Panics if `ptr` didn't come from [`MmapHeap::alloc`] or was already freed.
+    pub fn free(&mut self, ptr: RelPtr<u8>) {
+        let ptr = ptr.offset().expect("freeing a null pointer");
+        let top = self.header().top as usize;
+        assert!(
+            ptr >= DATA_OFFSET + size_of::<BlockHeader>() && ptr <= top && ptr.is_multiple_of(8),
+            "freeing a pointer outside the heap"
+        );
+
+        // a freed block's free list link overwrites the offset, so it won't look valid twice
+        let offset = *self.word(ptr - 8) as usize;
+        let valid = offset >= DATA_OFFSET
+            && offset.is_multiple_of(MIN_ALIGN)
+            && offset <= ptr - size_of::<BlockHeader>()
+            && self.block(offset).state == ALLOCATED
+            && (ptr - offset) >> self.block(offset).class.min(usize::BITS - 1) == 0;
+        assert!(
+            valid,
+            "freeing a pointer that wasn't allocated or was already freed"
+        );
+
+        let class = self.block(offset).class;
+        self.push(offset, class);
+    }
+
+    /// The whole mapped file, to resolve [`RelPtr`]s against.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.map.as_ptr(), self.map.len()) }
+    }
+
+    /// The whole mapped file mutably, to resolve [`RelPtr`]s against.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.map.as_ptr(), self.map.len()) }
+    }
+
+    /// Writes modified pages back to the file, blocking until done.
+    pub fn flush(&self) -> Result<(), B::Error> {
+        self.map.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::alloc::Layout;
+    use std::string::ToString;
+
+    use super::DATA_OFFSET;
+    use crate::test_util::{backend_tests, zeroed};
+    use crate::{LayoutError, MmapHeap, OpenOptions, ResizableBackend};
+
+    backend_tests!(alloc_free, short_file);
+
+    fn alloc_free<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut heap = MmapHeap::<B>::create(path)?;
+        let small = heap.alloc(Layout::new::<u64>())?;
+        let big = heap.alloc(Layout::from_size_align(10_000, 64).unwrap())?;
+        assert_eq!(big.offset().unwrap() % 64, 0);
+
+        heap.as_bytes_mut()[small.offset().unwrap()] = 7;
+        heap.free(big);
+        drop(heap);
+
+        let mut heap = MmapHeap::<B>::open(path)?;
+        assert_eq!(heap.as_bytes()[small.offset().unwrap()], 7);
+        // the free list was rebuilt, the freed block comes back
+        let again = heap.alloc(Layout::from_size_align(9_000, 16).unwrap())?;
+        assert_eq!(again.offset().unwrap() / 4096, big.offset().unwrap() / 4096);
+        Ok(())
+    }
+
+    fn short_file<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        // cut off in the middle of the heap header
+        zeroed::<B>(path, 100)?;
+        let e = MmapHeap::<B>::open(path).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            LayoutError::TooSmall {
+                len: 100,
+                required: DATA_OFFSET
+            }
+            .to_string()
+        );
+
+        let mut heap = MmapHeap::<B>::create(path)?;
+        let kept = heap.alloc(Layout::new::<[u8; 100]>())?;
+        let cut = heap.alloc(Layout::new::<[u8; 1000]>())?;
+        drop(heap);
+
+        // cut off in the middle of the second block
+        let file = B::open(path, &OpenOptions::new(), true)?;
+        B::set_file_len(&file, cut.offset().unwrap() + 100)?;
+        drop(file);
+
+        // the torn block is handed out again
+        let mut heap = MmapHeap::<B>::open(path)?;
+        heap.free(kept);
+        let again = heap.alloc(Layout::new::<[u8; 1000]>())?;
+        assert_eq!(again.offset(), cut.offset());
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "already freed")]
+    fn double_free() {
+        let path = "/tmp/mmap-wrapper-test-heap-double-free";
+        let _ = std::fs::remove_file(path);
+
+        let mut heap = MmapHeap::<crate::Memmap2Backend>::create(path).unwrap();
+        let ptr = heap.alloc(Layout::new::<[u8; 100]>()).unwrap();
+        heap.free(ptr);
+        heap.free(ptr);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "wasn't allocated")]
+    fn free_inside_allocation() {
+        let path = "/tmp/mmap-wrapper-test-heap-free-inside";
+        let _ = std::fs::remove_file(path);
+
+        let mut heap = MmapHeap::<crate::Memmap2Backend>::create(path).unwrap();
+        let ptr = heap.alloc(Layout::new::<[u64; 4]>()).unwrap();
+        let offset = ptr.offset().unwrap();
+        // looks like the offset of a block right at the end of the address space
+        heap.as_bytes_mut()[offset..offset + 8].copy_from_slice(&(!15u64).to_ne_bytes());
+        heap.free(crate::RelPtr::new(offset + 8));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "heap allocation too large")]
+    fn alloc_overflow() {
+        let path = "/tmp/mmap-wrapper-test-heap-overflow";
+        let _ = std::fs::remove_file(path);
+
+        let mut heap = MmapHeap::<crate::Memmap2Backend>::create(path).unwrap();
+        let _ = heap.alloc(Layout::from_size_align(isize::MAX as usize - 63, 64).unwrap());
+    }
+}
@@ -1,14 +1,92 @@
 #![doc = include_str!("../README.md")]
-#![cfg_attr(feature = "no_std", no_std)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-#[cfg(not(feature = "no_std"))]
+#[cfg(not(any(feature = "std", feature = "no_std")))]
+compile_error!("enable the `std` or `no_std` feature to get a mapping backend");
+
+// lets the derive macro refer to `::mmap_wrapper` from inside this crate
+extern crate self as mmap_wrapper;
+
+mod backend;
+mod borrow;
+mod error;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod futex;
+#[cfg(feature = "std")]
+mod hash_map;
+mod header;
+mod header_slice;
+mod heap;
+mod log;
+mod migrate;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod mutex;
+mod options;
+mod queue;
+mod rel_ptr;
+mod ring;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod robust_mutex;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod rwlock;
+mod safe;
+mod seqlock;
+mod shared;
+mod slice;
+#[cfg(test)]
+mod test_util;
+mod vec;
+mod wrapper;
+
+pub use backend::{MappingBackend, ResizableBackend};
+pub use borrow::{BorrowError, MmapRef, MmapRefMut};
+pub use error::LayoutError;
+#[cfg(feature = "std")]
+pub use hash_map::{MmapHashMap, MmapHashMapReader, TornSlotError};
+pub use header::{FileHeader, HeaderError, MAGIC};
+pub use header_slice::{MmapHeaderSlice, MmapHeaderSliceReader};
+pub use heap::MmapHeap;
+pub use log::{LogIter, MmapLog};
+pub use migrate::{Migrate, Migration};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use mutex::{MmapMutex, MmapMutexGuard};
+pub use options::OpenOptions;
+pub use queue::MmapMpmcQueue;
+pub use rel_ptr::RelPtr;
+pub use ring::MmapSpscRing;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use robust_mutex::{MmapRobustMutex, MmapRobustMutexGuard, RobustLockError};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use rwlock::{MmapRwLock, MmapRwLockReadGuard, MmapRwLockWriteGuard};
+#[doc(hidden)]
+pub use safe::LayoutHasher;
+pub use safe::MmapSafe;
+pub use seqlock::MmapSeqLock;
+pub use slice::{MmapSliceMutWrapper, MmapSliceWrapper};
+pub use vec::MmapVec;
+pub use wrapper::{MmapMutWrapper, MmapWrapper};
+
+#[cfg(feature = "derive")]
+pub use mmap_wrapper_derive::MmapSafe;
+
+#[cfg(feature = "std")]
 pub mod memmap2;
 
 #[cfg(feature = "no_std")]
 pub mod no_std;
 
-#[cfg(not(feature = "no_std"))]
-pub use memmap2::*;
+#[cfg(feature = "std")]
+pub use self::memmap2::Memmap2Backend;
 
 #[cfg(feature = "no_std")]
-pub use no_std::*;
+pub use self::no_std::{LibcBackend, MapError};
+
+/// The backend used when `B` is left out of [`MmapWrapper<T, B>`] and [`MmapMutWrapper<T, B>`],
+/// `Memmap2Backend` if the `std` feature is enabled, `LibcBackend` otherwise.
+#[cfg(feature = "std")]
+pub type DefaultBackend = Memmap2Backend;
+
+/// The backend used when `B` is left out of [`MmapWrapper<T, B>`] and [`MmapMutWrapper<T, B>`],
+/// `Memmap2Backend` if the `std` feature is enabled, `LibcBackend` otherwise.
+#[cfg(all(feature = "no_std", not(feature = "std")))]
+pub type DefaultBackend = LibcBackend;
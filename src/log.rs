@@ -0,0 +1,465 @@
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{DefaultBackend, LayoutError, OpenOptions, ResizableBackend};
+
+/// Stored at the start of the file, followed by the records.
+#[repr(C)]
+struct LogHeader {
+    // end of the last complete record, relative to the first one
+    committed: AtomicU64,
+    _reserved: u64,
+}
+
+/// Stored in front of every record, records are padded to 8 bytes.
+#[repr(C)]
+struct RecordHeader {
+    len: u32,
+    // over the length and the payload so a zeroed or torn header never checks out
+    crc: u32,
+}
+
+const DATA_OFFSET: usize = size_of::<LogHeader>();
+const RECORD_ALIGN: usize = 8;
+const MIN_CAP: usize = 4096;
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE) of `len` followed by `payload`.
+fn record_crc(len: u32, payload: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in len.to_le_bytes().iter().chain(payload) {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn record_size(len: usize) -> usize {
+    (size_of::<RecordHeader>() + len).next_multiple_of(RECORD_ALIGN)
+}
+
+/// An append-only log of variable-length byte records stored in a memory-mapped file.
+///
+/// Every record is written with its length and a CRC before the committed length in the
+/// header is moved past it, so a crash mid-append leaves the log as it was. Opening the log
+/// checks every committed record and drops a torn one at the end, in case the header made it
+/// to disk before the record did.
+///
+/// Records are addressed by the offset [`MmapLog::append`] returns.
+///
+/// Any number of handles can read the log, but only one at a time appends to it: the first
+/// append locks the file with [`ResizableBackend::try_lock`] until the log is dropped, and
+/// appending from other handles fails while it is held.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use mmap_wrapper::MmapLog;
+///
+/// let mut log: MmapLog = MmapLog::create("/tmp/log-mmap-test.bin").unwrap();
+/// log.append(b"first").unwrap();
+/// let second = log.append(b"second").unwrap();
+/// drop(log);
+///
+/// let log: MmapLog = MmapLog::open("/tmp/log-mmap-test.bin").unwrap();
+/// assert_eq!(log.get(second), Some(&b"second"[..]));
+/// assert_eq!(log.iter().count(), 2);
+/// assert_eq!(log.iter_from(second).map(|(_, r)| r).collect::<Vec<_>>(), [b"second"]);
+/// # }
+/// ```
+pub struct MmapLog<B: ResizableBackend = DefaultBackend> {
+    file: B::File,
+    map: B,
+    cap: usize,
+    // whether this handle holds the writer lock
+    writer: bool,
+}
+
+impl<B: ResizableBackend> MmapLog<B> {
+    /// Maps the log at `path`, creating it if necessary and dropping any existing records.
+    pub fn create(path: impl AsRef<B::Path>) -> Result<MmapLog<B>, B::Error> {
+        Self::open_with(path, OpenOptions::new().create(true).truncate(true))
+    }
+
+    /// Maps the existing log at `path`, keeping its records.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapLog<B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the log at `path`, opening it according to `options`.
+    ///
+    /// An empty file becomes an empty log and [`OpenOptions::truncate`] empties an existing one.
+    /// The log always starts at the beginning of the file, [`OpenOptions::offset`] is ignored.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapLog<B>, B::Error> {
+        let file = B::open(path.as_ref(), options, true)?;
+        let mut file_len = B::file_len(&file)?;
+        if options.truncate || file_len < DATA_OFFSET {
+            B::set_file_len(&file, DATA_OFFSET)?;
+            file_len = DATA_OFFSET;
+        }
+
+        let map = B::map_file(&file, file_len)?;
+        LayoutError::check::<LogHeader>(map.as_ptr(), map.len())?;
+
+        let mut log = MmapLog {
+            file,
+            map,
+            cap: file_len - DATA_OFFSET,
+            writer: false,
+        };
+
+        if options.truncate {
+            log.header().committed.store(0, Ordering::Release);
+        }
+        log.recover()?;
+
+        Ok(log)
+    }
+
+    fn header(&self) -> &LogHeader {
+        unsafe { &*self.map.as_ptr().cast::<LogHeader>() }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.map.as_ptr().add(DATA_OFFSET) }
+    }
+
+    /// Moves the committed length back to the end of the last valid record.
+    fn recover(&mut self) -> Result<(), B::Error> {
+        let committed = self.header().committed.load(Ordering::Acquire);
+
+        // another process may have grown the file since its length was read
+        if committed as usize > self.cap {
+            let file_len = B::file_len(&self.file)?;
+            if file_len.saturating_sub(DATA_OFFSET) > self.cap {
+                self.map.remap(&self.file, file_len)?;
+                self.cap = file_len - DATA_OFFSET;
+            }
+        }
+
+        let mut end = 0;
+        while let Some((_, next)) = self.record(end, (committed as usize).min(self.cap)) {
+            end = next;
+        }
+
+        // leaves the length alone if a live writer appended in the meantime
+        if end as u64 != committed {
+            let _ = self.header().committed.compare_exchange(
+                committed,
+                end as u64,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// End of the committed records this mapping can reach, records appended by
+    /// another process past it show up after reopening the log.
+    fn end(&self) -> usize {
+        (self.committed_len() as usize).min(self.cap)
+    }
+
+    /// The valid record at `offset` ending before `limit`, and where the next one starts.
+    fn record(&self, offset: usize, limit: usize) -> Option<(&[u8], usize)> {
+        if !offset.is_multiple_of(RECORD_ALIGN)
+            || offset.checked_add(size_of::<RecordHeader>())? > limit
+        {
+            return None;
+        }
+
+        let header = unsafe { self.data().add(offset).cast::<RecordHeader>().read() };
+        let next = offset.checked_add(record_size(header.len as usize))?;
+        if next > limit {
+            return None;
+        }
+
+        let payload = unsafe {
+            slice::from_raw_parts(
+                self.data().add(offset + size_of::<RecordHeader>()),
+                header.len as usize,
+            )
+        };
+        if record_crc(header.len, payload) != header.crc {
+            return None;
+        }
+
+        Some((payload, next))
+    }
+
+    /// Bytes taken by committed records.
+    pub fn committed_len(&self) -> u64 {
+        self.header().committed.load(Ordering::Acquire)
+    }
+
+    /// Returns `true` if no records have been committed.
+    pub fn is_empty(&self) -> bool {
+        self.committed_len() == 0
+    }
+
+    /// Makes room for `additional` more bytes of records, growing the file geometrically.
+    fn reserve(&mut self, additional: usize) -> Result<(), B::Error> {
+        let required = self.committed_len() as usize + additional;
+        if required <= self.cap {
+            return Ok(());
+        }
+
+        // an earlier writer may have grown the file past what this handle mapped, never shrink it
+        let current = B::file_len(&self.file)?.saturating_sub(DATA_OFFSET);
+        let cap = required
+            .max(self.cap.saturating_mul(2))
+            .max(current)
+            .max(MIN_CAP);
+        let file_len = DATA_OFFSET + cap;
+
+        if file_len > DATA_OFFSET + current {
+            B::set_file_len(&self.file, file_len)?;
+        }
+        self.map.remap(&self.file, file_len)?;
+        self.cap = cap;
+
+        Ok(())
+    }
+
+    /// Appends `record` and commits it, returning its offset.
+    ///
+    /// # Errors
+    /// Fails without writing anything while another handle is appending to the log.
+    ///
+    /// # Panics
+    /// Panics if `record` is 4 GiB or longer.
+    pub fn append(&mut self, record: &[u8]) -> Result<u64, B::Error> {
+        let len = u32::try_from(record.len()).expect("record too large");
+        let size = record_size(record.len());
+
+        if !self.writer {
+            B::try_lock(&self.file)?;
+            self.writer = true;
+            // a writer that died since this handle was opened may have left a torn record
+            self.recover()?;
+        }
+        self.reserve(size)?;
+
+        let offset = self.committed_len();
+        unsafe {
+            let at = self.data().add(offset as usize);
+            at.cast::<RecordHeader>().write(RecordHeader {
+                len,
+                crc: record_crc(len, record),
+            });
+            ptr::copy_nonoverlapping(
+                record.as_ptr(),
+                at.add(size_of::<RecordHeader>()),
+                record.len(),
+            );
+        }
+
+        // publish only once the whole record is in place
+        self.header()
+            .committed
+            .store(offset + size as u64, Ordering::Release);
+
+        Ok(offset)
+    }
+
+    /// The committed record starting at `offset`, `None` if there is none.
+    pub fn get(&self, offset: u64) -> Option<&[u8]> {
+        self.record(usize::try_from(offset).ok()?, self.end())
+            .map(|(r, _)| r)
+    }
+
+    /// Iterates over every committed record and its offset.
+    pub fn iter(&self) -> LogIter<'_, B> {
+        self.iter_from(0)
+    }
+
+    /// Iterates over the committed records starting with the one at `offset`.
+    pub fn iter_from(&self, offset: u64) -> LogIter<'_, B> {
+        LogIter {
+            log: self,
+            offset: offset as usize,
+            end: self.end(),
+            _inner: PhantomData,
+        }
+    }
+
+    /// Writes modified pages back to the file, blocking until done.
+    pub fn flush(&self) -> Result<(), B::Error> {
+        self.map.flush()
+    }
+}
+
+/// Iterator over the records of a [`MmapLog`], yielding each record's offset and bytes.
+pub struct LogIter<'a, B: ResizableBackend> {
+    log: &'a MmapLog<B>,
+    offset: usize,
+    end: usize,
+    _inner: PhantomData<&'a [u8]>,
+}
+
+impl<'a, B: ResizableBackend> Iterator for LogIter<'a, B> {
+    type Item = (u64, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u64, &'a [u8])> {
+        let (record, next) = self.log.record(self.offset, self.end)?;
+        let offset = self.offset as u64;
+        self.offset = next;
+        Some((offset, record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::test_util::{backend_tests, zeroed};
+    use crate::{MmapLog, MmapSliceMutWrapper, OpenOptions, ResizableBackend};
+
+    backend_tests!(
+        journal,
+        short_file,
+        reader_behind_writer,
+        one_writer_at_a_time,
+        torn_final_record
+    );
+
+    fn journal<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut log = MmapLog::<B>::create(path)?;
+        let mut offsets = [0; 100];
+        for (i, offset) in offsets.iter_mut().enumerate() {
+            *offset = log.append(&[i as u8; 100][..i])?;
+        }
+        drop(log);
+
+        let log = MmapLog::<B>::open(path)?;
+        assert_eq!(log.iter().count(), 100);
+        assert_eq!(log.get(offsets[42]), Some(&[42u8; 42][..]));
+        assert_eq!(log.get(offsets[42] + 8), None);
+        assert_eq!(
+            log.iter_from(offsets[98])
+                .map(|(_, r)| r.len())
+                .sum::<usize>(),
+            98 + 99
+        );
+
+        // offsets from nowhere near the log
+        assert_eq!(log.get(u64::MAX - 7), None);
+        assert_eq!(log.iter_from(u64::MAX - 7).count(), 0);
+        Ok(())
+    }
+
+    fn short_file<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        // too short for the header, starts out empty
+        zeroed::<B>(path, 5)?;
+        assert!(MmapLog::<B>::open(path)?.is_empty());
+
+        let mut log = MmapLog::<B>::create(path)?;
+        let mut last = 0;
+        for _ in 0..100 {
+            last = log.append(&[1; 100])?;
+        }
+        drop(log);
+
+        // cut off in the middle of the last record
+        let file = B::open(path, &OpenOptions::new(), true)?;
+        B::set_file_len(&file, 16 + last as usize + 50)?;
+        drop(file);
+
+        let mut log = MmapLog::<B>::open(path)?;
+        assert_eq!(log.committed_len(), last);
+        assert_eq!(log.iter().count(), 99);
+        assert_eq!(log.append(b"next")?, last);
+        Ok(())
+    }
+
+    fn reader_behind_writer<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut writer = MmapLog::<B>::create(path)?;
+        writer.append(b"first")?;
+        let reader = MmapLog::<B>::open(path)?;
+
+        // the writer grows the file past what the reader mapped
+        let mut last = 0;
+        for _ in 0..100 {
+            last = writer.append(&[1; 100])?;
+        }
+        assert!(reader.committed_len() > last);
+        assert_eq!(reader.get(last), None);
+        assert!(reader.iter().count() < 101);
+
+        // opening while the writer is live keeps its records
+        let reader = MmapLog::<B>::open(path)?;
+        assert_eq!(reader.iter().count(), 101);
+        assert_eq!(writer.committed_len(), reader.committed_len());
+        Ok(())
+    }
+
+    fn one_writer_at_a_time<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut first = MmapLog::<B>::create(path)?;
+        let mut second = MmapLog::<B>::open(path)?;
+        first.append(b"first")?;
+        assert!(second.append(b"second").is_err());
+
+        // grows the file well past what the second handle mapped
+        for _ in 0..100 {
+            first.append(&[1; 100])?;
+        }
+        let file = B::open(path, &OpenOptions::new(), false)?;
+        let len = B::file_len(&file)?;
+        drop(first);
+
+        let offset = second.append(b"second")?;
+        assert!(B::file_len(&file)? >= len);
+        drop(second);
+
+        let log = MmapLog::<B>::open(path)?;
+        assert_eq!(log.iter().count(), 102);
+        assert_eq!(log.get(offset), Some(&b"second"[..]));
+        Ok(())
+    }
+
+    fn torn_final_record<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut log = MmapLog::<B>::create(path)?;
+        log.append(b"kept")?;
+        let torn = log.append(b"torn record")?;
+        drop(log);
+
+        // the committed length made it to disk but the end of the record did not
+        MmapSliceMutWrapper::<u8, B>::open(path)?.write()[16 + torn as usize + 12] ^= 0xff;
+
+        let mut log = MmapLog::<B>::open(path)?;
+        assert_eq!(log.committed_len(), torn);
+        assert_eq!(
+            log.iter().map(|(_, r)| r).collect::<std::vec::Vec<_>>(),
+            [b"kept"]
+        );
+
+        // the next append takes its place
+        assert_eq!(log.append(b"next")?, torn);
+        Ok(())
+    }
+}
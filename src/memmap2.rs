@@ -1,158 +1,334 @@
-use core::mem::transmute_copy;
-use memmap2::{Mmap, MmapMut};
-use std::{marker::PhantomData, sync::Arc};
+use memmap2::{Mmap, MmapMut, MmapOptions, MmapRaw};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
 
-/// A wrapper wrapper for a memory-mapped file with data of type `T`.
-///
-/// # Safety
-///
-/// `T` must have a consistent memory layout to ensure that the data is casted correctly.
-///
-/// Use `#[repr(transparent)]` if `T` is a newtype wrapper around a single field otherwise `#[repr(C)]`.
-///
-/// # Example
-/// ```rust
-/// use mmap_wrapper::MmapWrapper;
-///
-/// #[repr(C)]
-/// struct MyStruct {
-///    thing1: i32,
-///    thing2: f64,
-/// }
-///
-/// let f = std::fs::File::options()
-///     .read(true)
-///     .write(true)
-///     .create(true)
-///     .truncate(false)
-///     .open("/tmp/mystruct-mmap-test.bin")
-///     .unwrap();
-///
-/// let _ = f.set_len(std::mem::size_of::<MyStruct>() as u64);
-///
-/// let m = unsafe {
-///     memmap2::Mmap::map(&f).unwrap()
-/// };
+use crate::{
+    LayoutError, MappingBackend, MmapMutWrapper, MmapSafe, MmapWrapper, OpenOptions,
+    ResizableBackend,
+};
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64"
+    )
+))]
+const O_NOFOLLOW: i32 = 0o100000;
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    not(any(
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64"
+    ))
+))]
+const O_NOFOLLOW: i32 = 0o400000;
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+const O_NOFOLLOW: i32 = 0x100;
+#[cfg(unix)]
+const LOCK_EX: i32 = 2;
+#[cfg(unix)]
+const LOCK_NB: i32 = 4;
+
+#[cfg(unix)]
+extern "C" {
+    fn flock(fd: i32, operation: i32) -> i32;
+}
+
+/// [`MappingBackend`] built on the `memmap2` crate.
 ///
-/// let m_wrapper = MmapWrapper::<MyStruct>::new(m);
-/// let mmap_backed_mystruct = unsafe {
-///    m_wrapper.get_inner()
-/// };
-/// ```
-pub struct MmapWrapper<T> {
-    raw: Arc<Mmap>,
-    _inner: PhantomData<T>,
+/// This is the [`DefaultBackend`](crate::DefaultBackend) when the `std` feature is enabled.
+#[derive(Debug)]
+pub struct Memmap2Backend {
+    raw: MmapRaw,
+    writable: bool,
 }
 
-impl<T> Clone for MmapWrapper<T> {
-    fn clone(&self) -> Self {
-        MmapWrapper {
-            raw: self.raw.clone(),
-            _inner: PhantomData,
+impl From<Mmap> for Memmap2Backend {
+    fn from(m: Mmap) -> Memmap2Backend {
+        Memmap2Backend {
+            raw: m.into(),
+            writable: false,
         }
     }
 }
 
-/// A mutable wrapper wrapper for a memory-mapped file with data of type `T`.
-///
-/// # Safety
-///
-/// `T` must have a consistent memory layout to ensure that the data is casted correctly.
-///
-/// Use `#[repr(transparent)]` if `T` is a newtype wrapper around a single field otherwise `#[repr(C)]`.
-///
-/// # Example
-/// ```rust
-/// use mmap_wrapper::MmapWrapper;
-///
-/// #[repr(C)]
-/// struct MyStruct {
-///    thing1: i32,
-///    thing2: f64,
-/// }
-///
-/// let f = std::fs::File::options()
-///     .read(true)
-///     .write(true)
-///     .create(true)
-///     .truncate(false)
-///     .open("/tmp/mystruct-mmap-test.bin")
-///     .unwrap();
-///
-/// let _ = f.set_len(std::mem::size_of::<MyStruct>() as u64);
-///
-/// let m = unsafe {
-///     memmap2::Mmap::map(&f).unwrap()
-/// };
-///
-/// let m_wrapper = MmapWrapper::<MyStruct>::new(m);
-/// let mmap_backed_mystruct = unsafe {
-///    m_wrapper.get_inner()
-/// };
-/// ```
-pub struct MmapMutWrapper<T> {
-    raw: Arc<MmapMut>,
-    _inner: PhantomData<T>,
+impl From<MmapMut> for Memmap2Backend {
+    fn from(m: MmapMut) -> Memmap2Backend {
+        Memmap2Backend {
+            raw: m.into(),
+            writable: true,
+        }
+    }
 }
 
-impl<T> Clone for MmapMutWrapper<T> {
-    fn clone(&self) -> Self {
-        MmapMutWrapper {
-            raw: self.raw.clone(),
-            _inner: PhantomData,
+impl Memmap2Backend {
+    /// Writes the file that [`MappingBackend::replace`] renames over `path` to `tmp`.
+    fn write_replacement(
+        path: &Path,
+        tmp: &Path,
+        tmp_options: &OpenOptions,
+        len: usize,
+        fill: impl FnOnce(&Self) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let old = fs::metadata(path)?;
+        let f = <Self as ResizableBackend>::open(tmp, tmp_options, true)?;
+        f.set_len(len as u64)?;
+
+        let new = Self::map_file(&f, len)?;
+        fill(&new)?;
+        new.flush()?;
+
+        // the mode of `tmp_options` could widen a private file, keep what it had
+        f.set_permissions(old.permissions())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            std::os::unix::fs::fchown(&f, Some(old.uid()), Some(old.gid()))?;
         }
+
+        Ok(())
     }
 }
 
-impl<T> From<Mmap> for MmapWrapper<T> {
-    fn from(m: Mmap) -> MmapWrapper<T> {
-        MmapWrapper::new(m)
+unsafe impl MappingBackend for Memmap2Backend {
+    type Path = Path;
+    type Error = io::Error;
+
+    fn map(
+        path: &Path,
+        options: &OpenOptions,
+        len: Option<usize>,
+        write: bool,
+    ) -> io::Result<Self> {
+        let f = <Self as ResizableBackend>::open(path, options, write)?;
+
+        let mut file_len = f.metadata()?.len();
+        let offset = options.offset as u64;
+        let required = match len {
+            Some(len) => offset
+                .checked_add(len as u64)
+                .ok_or(LayoutError::Overflow)?,
+            None => file_len.max(offset),
+        };
+        if write
+            && (options.truncate && file_len != required || options.grow && file_len < required)
+        {
+            f.set_len(required)?;
+            file_len = required;
+        }
+
+        if file_len < required {
+            return Err(LayoutError::TooSmall {
+                len: file_len as usize,
+                required: required as usize,
+            }
+            .into());
+        }
+
+        // memmap2 maps from the start of the page and hides the rest
+        let mut mmap_options = MmapOptions::new();
+        mmap_options
+            .offset(offset)
+            .len((required - offset) as usize);
+
+        let raw = if write {
+            mmap_options.map_raw(&f)?
+        } else {
+            mmap_options.map_raw_read_only(&f)?
+        };
+
+        Ok(Memmap2Backend {
+            raw,
+            writable: write,
+        })
+    }
+
+    fn replace(
+        path: &Path,
+        options: &OpenOptions,
+        len: usize,
+        fill: impl FnOnce(&Self) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        // left over files of an earlier attempt are overwritten
+        let mut tmp_options = options.clone();
+        tmp_options
+            .create(true)
+            .create_new(false)
+            .truncate(true)
+            .offset(0);
+
+        let res = Self::write_replacement(path, &tmp, &tmp_options, len, fill);
+        match res {
+            Ok(()) => fs::rename(&tmp, path),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    fn map_anon(len: usize) -> io::Result<Self> {
+        Ok(MmapMut::map_anon(len)?.into())
+    }
+
+    fn unmap(self) -> io::Result<()> {
+        // memmap2 ignores munmap errors, nothing more to report
+        drop(self);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.raw.flush()
+    }
+
+    fn writable(&self) -> bool {
+        self.writable
+    }
+
+    fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.raw.as_mut_ptr()
     }
 }
 
-impl<T> From<MmapMut> for MmapMutWrapper<T> {
-    fn from(m: MmapMut) -> MmapMutWrapper<T> {
-        unsafe { MmapMutWrapper::new(m) }
+unsafe impl ResizableBackend for Memmap2Backend {
+    type File = File;
+
+    fn open(path: &Path, options: &OpenOptions, write: bool) -> io::Result<File> {
+        let mut file_options = File::options();
+        file_options
+            .read(true)
+            .write(write)
+            .create(options.create)
+            .create_new(options.create_new);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            file_options.mode(options.mode);
+            if options.nofollow {
+                file_options.custom_flags(O_NOFOLLOW);
+            }
+        }
+
+        file_options.open(path)
+    }
+
+    fn file_len(file: &File) -> io::Result<usize> {
+        Ok(file.metadata()?.len() as usize)
+    }
+
+    fn set_file_len(file: &File, len: usize) -> io::Result<()> {
+        file.set_len(len as u64)
+    }
+
+    /// Does nothing on non-unix platforms.
+    fn try_lock(file: &File) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = file;
+
+        Ok(())
+    }
+
+    fn map_file(file: &File, len: usize) -> io::Result<Self> {
+        let raw = MmapOptions::new().len(len).map_raw(file)?;
+        Ok(Memmap2Backend {
+            raw,
+            writable: true,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn remap(&mut self, _file: &File, len: usize) -> io::Result<()> {
+        unsafe {
+            self.raw
+                .remap(len, memmap2::RemapOptions::new().may_move(true))
+        }
+    }
+}
+
+impl<T: MmapSafe> From<Mmap> for MmapWrapper<T, Memmap2Backend> {
+    fn from(m: Mmap) -> MmapWrapper<T, Memmap2Backend> {
+        Self::new(m)
     }
 }
 
-impl<T> MmapWrapper<T> {
-    /// # Safety
-    /// the backing mmap pointer must point to valid
-    /// memory for type T [T likely has to be repr(C)]
-    pub fn new(m: Mmap) -> MmapWrapper<T> {
-        // check that size of m matches
-        // size of inner type
-        MmapWrapper {
-            raw: Arc::new(m),
-            _inner: PhantomData,
+impl<T: MmapSafe> From<MmapMut> for MmapMutWrapper<T, Memmap2Backend> {
+    fn from(m: MmapMut) -> MmapMutWrapper<T, Memmap2Backend> {
+        Self::new(m)
+    }
+}
+
+impl<T: MmapSafe> MmapWrapper<T, Memmap2Backend> {
+    /// Wraps an existing read-only mapping.
+    ///
+    /// # Panics
+    /// Panics if `m` is too short or misaligned for `T`,
+    /// use [`MmapWrapper::try_new`] to handle this case.
+    pub fn new(m: Mmap) -> MmapWrapper<T, Memmap2Backend> {
+        match Self::try_new(m) {
+            Ok(w) => w,
+            Err(e) => panic!("{e}"),
         }
     }
 
-    pub fn get_inner<'a>(&self) -> &'a T {
-        unsafe { &*self.raw.as_ptr().cast::<T>() }
+    /// Same as [`MmapWrapper::new`] but checks that `m` is at least
+    /// `size_of::<T>()` bytes long and aligned for `T`.
+    pub fn try_new(m: Mmap) -> io::Result<MmapWrapper<T, Memmap2Backend>> {
+        Self::from_backend(m.into())
     }
 }
 
-impl<T> MmapMutWrapper<T> {
-    /// # Safety
-    /// the backing mmap pointer must point to valid
-    /// memory for type T [T likely has to be repr(C)]
-    pub unsafe fn new(m: MmapMut) -> MmapMutWrapper<T> {
-        MmapMutWrapper {
-            raw: Arc::new(m),
-            _inner: PhantomData,
+impl<T: MmapSafe> MmapMutWrapper<T, Memmap2Backend> {
+    /// Wraps an existing writable mapping.
+    ///
+    /// # Panics
+    /// Panics if `m` is too short or misaligned for `T`,
+    /// use [`MmapMutWrapper::try_new`] to handle this case.
+    pub fn new(m: MmapMut) -> MmapMutWrapper<T, Memmap2Backend> {
+        match Self::try_new(m) {
+            Ok(w) => w,
+            Err(e) => panic!("{e}"),
         }
     }
 
-    pub fn get_inner<'a>(&mut self) -> &'a mut T {
-        unsafe { &mut *self.raw.as_ptr().cast_mut().cast::<T>() }
+    /// Same as [`MmapMutWrapper::new`] but checks that `m` is at least
+    /// `size_of::<T>()` bytes long and aligned for `T`.
+    pub fn try_new(m: MmapMut) -> io::Result<MmapMutWrapper<T, Memmap2Backend>> {
+        Self::from_backend(m.into())
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "derive"))]
 mod tests {
 
+    #[derive(MmapSafe)]
+    #[repr(C)]
     struct TestStruct {
         _thing1: i32,
     }
@@ -162,7 +338,8 @@ mod tests {
         thread,
     };
 
-    use crate::MmapMutWrapper;
+    use super::Memmap2Backend;
+    use crate::{BorrowError, LayoutError, MmapMutWrapper, MmapSafe, MmapWrapper};
 
     #[test]
     fn arc_thread_test() {
@@ -170,18 +347,94 @@ mod tests {
         f.set_len(size_of::<TestStruct>().try_into().unwrap())
             .unwrap();
         let m = unsafe { memmap2::MmapMut::map_mut(&f).unwrap() };
-        let m: MmapMutWrapper<TestStruct> = unsafe { MmapMutWrapper::new(m) };
+        let m: MmapMutWrapper<TestStruct> = MmapMutWrapper::<TestStruct>::new(m);
 
-        let m_clone = m.clone();
+        let mut m_clone = m.clone();
 
         let t = thread::spawn(move || {
-            let _ = m_clone;
+            m_clone.write()._thing1 = 7;
         });
 
         let _ = t.join();
 
+        assert_eq!(m.read()._thing1, 7);
+
         drop(m);
 
         fs::remove_file("arc_thread_test").unwrap();
     }
+
+    #[test]
+    fn clone_cannot_alias_write() {
+        let m = memmap2::MmapMut::map_anon(size_of::<TestStruct>()).unwrap();
+        let mut m: MmapMutWrapper<TestStruct> = MmapMutWrapper::<TestStruct>::new(m);
+        let mut m_clone = m.clone();
+
+        let reader = m_clone.read();
+        assert_eq!(m.try_write().err(), Some(BorrowError));
+        drop(reader);
+
+        let mut writer = m.write();
+        writer._thing1 = 1;
+        assert!(m_clone.try_read().is_err());
+        assert!(m_clone.try_write().is_err());
+        drop(writer);
+
+        assert_eq!(m_clone.write()._thing1, 1);
+    }
+
+    #[test]
+    fn path_constructors() {
+        let path = "/tmp/mmap-wrapper-test-path-constructors";
+        let _ = fs::remove_file(path);
+
+        assert_eq!(
+            MmapMutWrapper::<TestStruct>::open(path)
+                .err()
+                .map(|e| e.kind()),
+            Some(std::io::ErrorKind::NotFound)
+        );
+
+        let mut m = MmapMutWrapper::<TestStruct>::create(path).unwrap();
+        m.write()._thing1 = 42;
+
+        let m = MmapWrapper::<TestStruct>::open(path).unwrap();
+        assert_eq!(m.read()._thing1, 42);
+
+        File::create(path).unwrap();
+        assert_eq!(
+            MmapWrapper::<TestStruct>::open(path)
+                .err()
+                .map(|e| e.kind()),
+            Some(std::io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn try_new_too_small() {
+        let m = memmap2::MmapMut::map_anon(0).unwrap();
+        let res = MmapMutWrapper::<TestStruct>::try_new(m);
+
+        assert_eq!(
+            res.err().map(|e| e.to_string()),
+            Some(format!(
+                "mapping is 0 bytes but the inner type needs {}",
+                size_of::<TestStruct>()
+            ))
+        );
+    }
+
+    #[test]
+    fn read_only_is_not_mutable() {
+        let path = "/tmp/mmap-wrapper-test-read-only-backend";
+        let _ = fs::remove_file(path);
+        MmapMutWrapper::<TestStruct>::create(path).unwrap();
+
+        let m = unsafe { memmap2::Mmap::map(&File::open(path).unwrap()).unwrap() };
+        let res = MmapMutWrapper::<TestStruct, Memmap2Backend>::from_backend(m.into());
+        assert_eq!(
+            res.err().map(|e| e.to_string()),
+            Some(LayoutError::ReadOnly.to_string())
+        );
+    }
 }
@@ -0,0 +1,334 @@
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr;
+
+use crate::{FileHeader, HeaderError, LayoutError, MappingBackend, MmapSafe, OpenOptions};
+
+/// One step of a schema migration, from version `from` to `from + 1`.
+///
+/// Implemented by [`Migration`], which is what you want to build a registry from.
+pub trait Migrate {
+    /// Version of the files this step upgrades.
+    fn from(&self) -> u32;
+
+    /// Checks that `header` was written for the old type of this step.
+    fn check(&self, header: &FileHeader) -> Result<(), HeaderError>;
+
+    /// Bytes taken by the header and old type.
+    fn old_len(&self) -> usize;
+
+    /// Bytes taken by the header and new type.
+    fn new_len(&self) -> usize;
+
+    /// Builds the new file contents at `new` from the old ones at `old`.
+    ///
+    /// # Safety
+    ///
+    /// `old` must point to [`Migrate::old_len`] bytes that passed [`Migrate::check`] and `new`
+    /// to [`Migrate::new_len`] zeroed writable bytes, both aligned for the header and the types
+    /// of this step and not overlapping.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HeaderError::VersionOverflow`] for a step from `u32::MAX`, writing nothing.
+    unsafe fn run(&self, old: *const u8, new: *mut u8) -> Result<(), HeaderError>;
+}
+
+/// Upgrades files holding an `Old` with schema version `from` to a `New` with version `from + 1`.
+///
+/// The new value starts out zeroed, the closure only needs to fill in what changed.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{Migration, MmapMutWrapper, MmapSafe, OpenOptions};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct V1 {
+///    count: u32,
+/// }
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct V2 {
+///    count: u64,
+///    total: u64,
+/// }
+///
+/// let path = "/tmp/mystruct-mmap-migrate-test.bin";
+/// # let _ = std::fs::remove_file(path);
+/// OpenOptions::new().create(true).grow(true).header(1).map_mut::<V1>(path).unwrap().write().count = 7;
+///
+/// let m_wrapper = MmapMutWrapper::<V2>::open_migrated(
+///     path,
+///     OpenOptions::new().header(2),
+///     &[&Migration::new(1, |old: &V1, new: &mut V2| {
+///         new.count = old.count as u64;
+///         new.total = old.count as u64;
+///     })],
+/// )
+/// .unwrap();
+/// assert_eq!(m_wrapper.read().total, 7);
+/// # }
+/// ```
+pub struct Migration<Old, New, F> {
+    from: u32,
+    f: F,
+    _types: PhantomData<fn(&Old, &mut New)>,
+}
+
+impl<Old, New, F> Migration<Old, New, F>
+where
+    Old: MmapSafe,
+    New: MmapSafe,
+    F: Fn(&Old, &mut New),
+{
+    /// Migration from version `from` to `from + 1` running `f`.
+    pub fn new(from: u32, f: F) -> Migration<Old, New, F> {
+        Migration {
+            from,
+            f,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<Old, New, F> Migrate for Migration<Old, New, F>
+where
+    Old: MmapSafe,
+    New: MmapSafe,
+    F: Fn(&Old, &mut New),
+{
+    fn from(&self) -> u32 {
+        self.from
+    }
+
+    fn check(&self, header: &FileHeader) -> Result<(), HeaderError> {
+        header.validate::<Old>(self.from)
+    }
+
+    fn old_len(&self) -> usize {
+        FileHeader::data_offset::<Old>() + size_of::<Old>()
+    }
+
+    fn new_len(&self) -> usize {
+        FileHeader::data_offset::<New>() + size_of::<New>()
+    }
+
+    unsafe fn run(&self, old: *const u8, new: *mut u8) -> Result<(), HeaderError> {
+        let to = self
+            .from
+            .checked_add(1)
+            .ok_or(HeaderError::VersionOverflow)?;
+
+        unsafe {
+            let old_t = &*old.add(FileHeader::data_offset::<Old>()).cast::<Old>();
+            let new_t = &mut *new.add(FileHeader::data_offset::<New>()).cast::<New>();
+            (self.f)(old_t, new_t);
+
+            new.cast::<FileHeader>().write(FileHeader::new::<New>(to));
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the steps of `migrations` on the file at `path` until it reaches version `to`
+/// or a version none of them upgrades.
+///
+/// Each step writes a copy of the file with the mapped region rewritten next to it and
+/// renames it over the old one (see [`MappingBackend::replace`]), a step interrupted halfway
+/// leaves the previous version intact.
+pub(crate) fn run<B: MappingBackend>(
+    path: &B::Path,
+    options: &OpenOptions,
+    to: u32,
+    migrations: &[&dyn Migrate],
+) -> Result<(), B::Error> {
+    // only look at the header, creating the file if asked to
+    let mut probe = options.clone();
+    probe.truncate(false).grow(options.grow || options.truncate);
+
+    loop {
+        let file = B::map(path, &probe, None, true)?;
+        if file.len() < size_of::<FileHeader>() {
+            // a new file, opening it writes the header
+            return Ok(());
+        }
+        let header = unsafe { file.as_ptr().cast::<FileHeader>().read() };
+        drop(file);
+
+        if header.is_blank() || header.version() >= to {
+            return Ok(());
+        }
+
+        let Some(step) = migrations.iter().find(|m| m.from() == header.version()) else {
+            return Ok(());
+        };
+        step.check(&header)?;
+
+        // the whole file, anything around the mapped region is carried over as is
+        let mut whole = options.clone();
+        whole
+            .create(false)
+            .create_new(false)
+            .truncate(false)
+            .grow(false)
+            .offset(0);
+        let old = B::map(path, &whole, None, false)?;
+
+        let (offset, old_len, new_len) = (options.offset, step.old_len(), step.new_len());
+        if old.len() < offset + old_len {
+            return Err(LayoutError::TooSmall {
+                len: old.len().saturating_sub(offset),
+                required: old_len,
+            }
+            .into());
+        }
+
+        B::replace(path, options, old.len().max(offset + new_len), |new| {
+            unsafe {
+                ptr::copy_nonoverlapping(old.as_ptr(), new.as_ptr(), old.len());
+                let data = new.as_ptr().add(offset);
+                data.write_bytes(0, new_len);
+                step.run(old.as_ptr().add(offset), data)?;
+            }
+            Ok(())
+        })?;
+    }
+}
+
+#[cfg(all(test, feature = "std", feature = "derive"))]
+mod tests {
+    use crate::{HeaderError, Migrate, Migration, MmapMutWrapper, MmapSafe, OpenOptions};
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct V1 {
+        count: u32,
+    }
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct V2 {
+        count: u64,
+    }
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct V3 {
+        count: u64,
+        history: [u64; 512],
+    }
+
+    fn create_v1(path: &str, count: u32) {
+        let _ = std::fs::remove_file(path);
+        let mut rw = OpenOptions::new()
+            .create(true)
+            .grow(true)
+            .header(1)
+            .map_mut::<V1>(path)
+            .unwrap();
+        rw.write().count = count;
+    }
+
+    #[test]
+    fn migrate_chain() {
+        let path = "/tmp/mmap-wrapper-test-migrate-chain";
+        create_v1(path, 3);
+
+        let v1_v2 = Migration::new(1, |old: &V1, new: &mut V2| new.count = old.count as u64);
+        let v2_v3 = Migration::new(2, |old: &V2, new: &mut V3| {
+            new.count = old.count;
+            new.history[0] = old.count;
+        });
+        let migrations: [&dyn Migrate; 2] = [&v1_v2, &v2_v3];
+
+        // stops at the requested version even if more steps are registered
+        let v2 =
+            MmapMutWrapper::<V2>::open_migrated(path, OpenOptions::new().header(2), &migrations)
+                .unwrap();
+        assert_eq!(v2.read().count, 3);
+        drop(v2);
+
+        let v3 =
+            MmapMutWrapper::<V3>::open_migrated(path, OpenOptions::new().header(3), &migrations)
+                .unwrap();
+        assert_eq!(v3.read().count, 3);
+        assert_eq!(v3.read().history[..2], [3, 0]);
+        drop(v3);
+
+        // already migrated, opens without running anything
+        let v3 = OpenOptions::new().header(3).map::<V3>(path).unwrap();
+        assert_eq!(v3.read().count, 3);
+    }
+
+    #[test]
+    fn migrate_interrupted() {
+        let path = "/tmp/mmap-wrapper-test-migrate-interrupted";
+        create_v1(path, 5);
+
+        let crash = Migration::new(1, |_: &V1, _: &mut V2| panic!("crash mid-migration"));
+        let res = std::panic::catch_unwind(|| {
+            MmapMutWrapper::<V2>::open_migrated(path, OpenOptions::new().header(2), &[&crash])
+        });
+        assert!(res.is_err());
+
+        // the old version is still there and the next attempt picks it up
+        assert_eq!(
+            OpenOptions::new()
+                .header(1)
+                .map::<V1>(path)
+                .unwrap()
+                .read()
+                .count,
+            5
+        );
+        let v1_v2 = Migration::new(1, |old: &V1, new: &mut V2| new.count = old.count as u64);
+        let v2 = MmapMutWrapper::<V2>::open_migrated(path, OpenOptions::new().header(2), &[&v1_v2])
+            .unwrap();
+        assert_eq!(v2.read().count, 5);
+    }
+
+    #[test]
+    fn migrate_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = "/tmp/mmap-wrapper-test-migrate-permissions";
+        create_v1(path, 2);
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        // the default mode of the options is 0o644
+        let v1_v2 = Migration::new(1, |old: &V1, new: &mut V2| new.count = old.count as u64);
+        MmapMutWrapper::<V2>::open_migrated(path, OpenOptions::new().header(2), &[&v1_v2]).unwrap();
+
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn migrate_from_last_version() {
+        let last = Migration::new(u32::MAX, |_: &V1, _: &mut V2| unreachable!());
+        let res = unsafe { last.run(std::ptr::null(), std::ptr::null_mut()) };
+        assert_eq!(res, Err(HeaderError::VersionOverflow));
+    }
+
+    #[test]
+    fn migrate_missing_step() {
+        let path = "/tmp/mmap-wrapper-test-migrate-missing";
+        create_v1(path, 1);
+
+        let v2_v3 = Migration::new(2, |old: &V2, new: &mut V3| new.count = old.count);
+        let e = MmapMutWrapper::<V3>::open_migrated(path, OpenOptions::new().header(3), &[&v2_v3])
+            .err()
+            .unwrap();
+        assert_eq!(
+            *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+            HeaderError::Version {
+                found: 1,
+                expected: 3
+            }
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{futex, LayoutHasher, MmapRef, MmapSafe};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// locked and someone may be sleeping on the futex
+const CONTENDED: u32 = 2;
+
+/// A mutex that can be stored in a mapped file and locked by every process mapping it.
+///
+/// Waiting is done with Linux futexes shared between processes. Locking writes to the mapping,
+/// so it is only offered on the [`MmapRef`] of a read-write mapping like [`MmapMutWrapper::read`],
+/// use [`MmapMutWrapper::view`] to reach a mutex inside a bigger struct.
+/// A zeroed `MmapMutex` is unlocked.
+///
+/// [`MmapMutWrapper::read`]: crate::MmapMutWrapper::read
+/// [`MmapMutWrapper::view`]: crate::MmapMutWrapper::view
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use mmap_wrapper::{MmapMutWrapper, MmapMutex};
+///
+/// # let _ = std::fs::remove_file("/tmp/mutex-mmap-test.bin");
+/// let counter = MmapMutWrapper::<MmapMutex<u64>>::create("/tmp/mutex-mmap-test.bin").unwrap();
+/// // another process opening the file locks the same mutex
+/// let other = MmapMutWrapper::<MmapMutex<u64>>::open("/tmp/mutex-mmap-test.bin").unwrap();
+///
+/// *counter.read().lock() += 1;
+/// *other.read().lock() += 1;
+/// assert_eq!(*counter.read().lock(), 2);
+/// # }
+/// ```
+///
+/// Read-only mappings can't lock it:
+/// ```rust,compile_fail
+/// use mmap_wrapper::{MmapMutex, MmapWrapper};
+///
+/// let counter = MmapWrapper::<MmapMutex<u64>>::open("/tmp/mutex-mmap-test.bin").unwrap();
+/// *counter.read().lock() += 1;
+/// ```
+#[repr(C)]
+pub struct MmapMutex<T> {
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: MmapSafe> MmapSafe for MmapMutex<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("MmapMutex")
+        .write_u64(T::LAYOUT_HASH)
+        .finish();
+}
+
+unsafe impl<T: Send> Send for MmapMutex<T> {}
+unsafe impl<T: Send> Sync for MmapMutex<T> {}
+
+impl<T> MmapMutex<T> {
+    /// An unlocked mutex holding `value`.
+    pub const fn new(value: T) -> MmapMutex<T> {
+        MmapMutex {
+            state: AtomicU32::new(UNLOCKED),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        // spin briefly in case the holder is about to let go
+        for _ in 0..100 {
+            if self.state.load(Ordering::Relaxed) == UNLOCKED
+                && self
+                    .state
+                    .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+
+        // marking it contended makes the holder wake us up
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            futex::wait(&self.state, CONTENDED, None);
+        }
+    }
+
+    /// The protected value, no locking needed with exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex::wake(&self.state, 1);
+        }
+    }
+}
+
+// every lock and unlock writes the lock word
+impl<T> MmapRef<'_, MmapMutex<T>> {
+    /// Blocks until the mutex is locked by this thread.
+    pub fn lock(&self) -> MmapMutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended();
+        }
+
+        MmapMutexGuard { mutex: self }
+    }
+
+    /// Locks the mutex if nobody holds it.
+    pub fn try_lock(&self) -> Option<MmapMutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MmapMutexGuard { mutex: self })
+    }
+}
+
+impl<T> fmt::Debug for MmapMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the mapping may be read-only, so don't lock it to show the value
+        let locked = self.state.load(Ordering::Relaxed) != UNLOCKED;
+        f.debug_struct("MmapMutex")
+            .field("locked", &locked)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Holds a [`MmapMutex`] locked, unlocking it when dropped.
+pub struct MmapMutexGuard<'a, T> {
+    mutex: &'a MmapMutex<T>,
+}
+
+impl<T> Deref for MmapMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MmapMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MmapMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{MmapMutWrapper, MmapMutex};
+
+    #[test]
+    fn separate_mappings_exclude_each_other() {
+        let path = "/tmp/mmap-wrapper-test-mutex";
+        let _ = std::fs::remove_file(path);
+        MmapMutWrapper::<MmapMutex<[u64; 2]>>::create(path).unwrap();
+
+        // every thread maps the file itself, like separate processes would
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let map = MmapMutWrapper::<MmapMutex<[u64; 2]>>::open(path).unwrap();
+                    let mutex = map.read();
+                    for _ in 0..10_000 {
+                        let mut pair = mutex.lock();
+                        assert_eq!(pair[0], pair[1]);
+                        pair[0] += 1;
+                        pair[1] += 1;
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        let map = MmapMutWrapper::<MmapMutex<[u64; 2]>>::open(path).unwrap();
+        let mutex = map.read();
+        assert_eq!(*mutex.lock(), [40_000; 2]);
+
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+}
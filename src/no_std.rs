@@ -1,247 +1,645 @@
-#[cfg(not(target_family = "unix"))]
-compile_error!("no_std feature only supports unix based operating systems");
+// the flags below are Linux values, other unix systems number them differently
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+compile_error!("no_std feature only supports Linux and Android");
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "mips32r6",
+    target_arch = "mips64r6",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+))]
+compile_error!("no_std feature does not support the open and mmap flags of this architecture");
 
-use core::ffi::{c_char, c_int, c_longlong, c_uint, c_void, CStr};
-use core::marker::PhantomData;
-use core::mem::size_of;
-use core::mem::transmute_copy;
+use core::ffi::{c_char, c_int, c_uint, c_void, CStr};
+use core::fmt;
+use core::mem;
 use core::ptr;
 
+use crate::{
+    HeaderError, LayoutError, MappingBackend, MmapMutWrapper, MmapSafe, MmapWrapper, OpenOptions,
+    ResizableBackend,
+};
+
 const O_RDONLY: c_int = 0;
 const O_RDWR: c_int = 2;
-const O_CREAT: c_int = 64;
+const O_CREAT: c_int = 0o100;
+const O_EXCL: c_int = 0o200;
+const O_CLOEXEC: c_int = 0o2000000;
+#[cfg(any(
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64"
+))]
+const O_NOFOLLOW: c_int = 0o100000;
+#[cfg(not(any(
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64"
+)))]
+const O_NOFOLLOW: c_int = 0o400000;
 const PROT_READ: c_int = 1;
 const PROT_WRITE: c_int = 2;
 const MAP_SHARED: c_int = 1;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
 const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+const SEEK_END: c_int = 2;
+const MS_SYNC: c_int = 4;
+const MREMAP_MAYMOVE: c_int = 1;
+const PATH_MAX: usize = 4096;
+const EFBIG: c_int = 27;
+const ENAMETOOLONG: c_int = 36;
+const EOVERFLOW: c_int = 75;
+const AT_FDCWD: c_int = -100;
+const STATX_MODE: c_uint = 0x2;
+const STATX_UID: c_uint = 0x8;
+const STATX_GID: c_uint = 0x10;
+const LOCK_EX: c_int = 2;
+const LOCK_NB: c_int = 4;
+// address of empty mappings, aligned for any `T` that fits in a page
+const EMPTY_ADDR: usize = 4096;
 
 #[allow(non_camel_case_types)]
-type off_t = usize;
+type size_t = usize;
+// the `*64` calls take 64-bit offsets even on 32-bit targets
+#[allow(non_camel_case_types)]
+type off64_t = i64;
+
+/// The start of `struct statx`, which unlike `struct stat` has the same layout everywhere.
+#[repr(C)]
+struct Statx {
+    mask: u32,
+    blksize: u32,
+    attributes: u64,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    mode: u16,
+    _rest: [u8; 226],
+}
+
+const _: () = assert!(mem::size_of::<Statx>() == 256);
 
 extern "C" {
-    // Could technically support Linux 32bit large file support (i.e mmap64) but we're only mapping Sized structs so shrug
     fn open(pathname: *const c_char, flags: c_int, mode: c_uint) -> c_int;
-    fn mmap(
+    fn mmap64(
         addr: *mut c_void,
-        length: off_t,
+        length: size_t,
         prot: c_int,
         flags: c_int,
         fd: c_int,
-        offset: c_longlong,
+        offset: off64_t,
     ) -> *mut c_void;
     fn close(fd: c_int) -> c_int;
-    fn ftruncate(fd: c_int, length: c_longlong) -> c_int;
-    fn munmap(addr: *mut c_void, length: off_t) -> c_int;
+    fn ftruncate64(fd: c_int, length: off64_t) -> c_int;
+    fn lseek64(fd: c_int, offset: off64_t, whence: c_int) -> off64_t;
+    fn munmap(addr: *mut c_void, length: size_t) -> c_int;
+    fn msync(addr: *mut c_void, length: size_t, flags: c_int) -> c_int;
+    fn getpagesize() -> c_int;
+    fn rename(oldpath: *const c_char, newpath: *const c_char) -> c_int;
+    fn unlink(pathname: *const c_char) -> c_int;
+    fn statx(
+        dirfd: c_int,
+        pathname: *const c_char,
+        flags: c_int,
+        mask: c_uint,
+        statxbuf: *mut Statx,
+    ) -> c_int;
+    fn fchmod(fd: c_int, mode: c_uint) -> c_int;
+    fn fchown(fd: c_int, owner: c_uint, group: c_uint) -> c_int;
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+    fn mremap(
+        addr: *mut c_void,
+        old_len: size_t,
+        new_len: size_t,
+        flags: c_int,
+        ...
+    ) -> *mut c_void;
+    #[cfg_attr(target_os = "linux", link_name = "__errno_location")]
+    #[cfg_attr(target_os = "android", link_name = "__errno")]
+    fn errno_location() -> *mut c_int;
 }
 
-/// A wrapper for a memory-mapped file with data of type `T`.
-///
-/// # Safety
-///
-/// `T` must have a consistent memory layout to ensure that the data is casted correctly.
-///
-/// Use `#[repr(transparent)]` if `T` is a newtype wrapper around a single field otherwise `#[repr(C)]`.
-///
-/// # Example
-/// ```rust
-/// use mmap_wrapper::MmapWrapper;
-///
-/// // repr(C) for consistent memory layout
-/// #[repr(C)]
-/// struct MyStruct {
-///    thing1: i32,
-///    thing2: f64,
-/// }
-///
-/// let m_wrapper = MmapWrapper::<MyStruct>::new(c"/tmp/mystruct-mmap-test.bin").unwrap();
-/// let mmap_backed_mystruct = unsafe {
-///    m_wrapper.get_inner()
-/// };
-/// ```
-pub struct MmapWrapper<T> {
-    raw: *mut c_void,
-    _inner: PhantomData<T>,
+/// Why a file could not be mapped, carrying the `errno` of the failing call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// `open` failed.
+    Open(c_int),
+    /// `ftruncate` failed while resizing the file.
+    Truncate(c_int),
+    /// `lseek` failed while reading the file size.
+    Seek(c_int),
+    /// `mmap` failed.
+    Mmap(c_int),
+    /// `msync` failed while flushing the mapping.
+    Flush(c_int),
+    /// `munmap` failed.
+    Unmap(c_int),
+    /// `rename` failed while replacing the file.
+    Rename(c_int),
+    /// Reading or copying the permissions and owner of a replaced file failed.
+    Permissions(c_int),
+    /// `flock` failed, `EWOULDBLOCK` if another open of the file holds the lock.
+    Lock(c_int),
+    /// The file cannot hold a value of type `T`.
+    Layout(LayoutError),
+    /// The file header does not match the type it was opened as.
+    Header(HeaderError),
 }
 
-/// A mutable wrapper for a memory-mapped file with data of type `T`.
-///
-/// # Safety
-///
-/// `T` must have a consistent memory layout to ensure that the data is casted correctly.
-///
-/// Use `#[repr(transparent)]` if `T` is a newtype wrapper around a single field otherwise `#[repr(C)]`.
-///
-/// # Example
-/// ```rust
-/// use mmap_wrapper::MmapWrapper;
-///
-/// // repr(C) for consistent memory layout
-/// #[repr(C)]
-/// struct MyStruct {
-///    thing1: i32,
-///    thing2: f64,
-/// }
-///
-/// let m_wrapper = MmapWrapper::<MyStruct>::new(c"/tmp/mystruct-mmap-test.bin").unwrap();
-/// let mmap_backed_mystruct = unsafe {
-///    m_wrapper.get_inner()
-/// };
-/// ```
-pub struct MmapMutWrapper<T> {
-    raw: *mut c_void,
-    _inner: PhantomData<T>,
+impl From<LayoutError> for MapError {
+    fn from(e: LayoutError) -> MapError {
+        MapError::Layout(e)
+    }
 }
 
-impl<T> MmapWrapper<T> {
-    /// Maps a file to memory, creating it if necessary.
-    ///
-    /// # Safety
-    ///
-    /// - The `T` type must be `#[repr(C)]` or `#[repr(transparent)]` to ensure a consistent memory layout.
-    /// - The file is truncated to the size of `T`. If this fails, the file descriptor is closed, and an error is returned.
-    /// - Memory mapping is done with either (`PROT_READ | PROT_WRITE` or `PROT_READ`) and `MAP_SHARED`. If the mapping fails, the file descriptor is closed, and an error is returned.
-    ///
-    /// # Errors
-    ///
-    /// - Returns `Err` if the file cannot be opened, truncated, or mapped.
-    /// - Returns `Err(-1)` specifically if memory mapping fails.
-    fn map(path: &CStr, write: bool) -> Result<*mut c_void, c_int> {
-        let fd = unsafe {
-            let flag = if write { O_RDWR } else { O_RDONLY };
-            open(path.as_ptr(), O_CREAT | flag, 0o644)
-        };
-        if fd < 0 {
-            return Err(fd);
+impl From<HeaderError> for MapError {
+    fn from(e: HeaderError) -> MapError {
+        MapError::Header(e)
+    }
+}
+
+impl MapError {
+    /// The `errno` of the failing call, `None` for [`MapError::Layout`] and [`MapError::Header`].
+    pub fn errno(&self) -> Option<c_int> {
+        match self {
+            MapError::Open(e)
+            | MapError::Truncate(e)
+            | MapError::Seek(e)
+            | MapError::Mmap(e)
+            | MapError::Flush(e)
+            | MapError::Unmap(e)
+            | MapError::Rename(e)
+            | MapError::Permissions(e)
+            | MapError::Lock(e) => Some(*e),
+            MapError::Layout(_) | MapError::Header(_) => None,
         }
+    }
+}
 
-        if write {
-            let res = unsafe { ftruncate(fd, size_of::<T>() as c_longlong) };
-            if res < 0 {
-                unsafe { close(fd) };
-                return Err(res);
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Open(e) => write!(f, "failed to open file (errno {e})"),
+            MapError::Truncate(e) => write!(f, "failed to resize file (errno {e})"),
+            MapError::Seek(e) => write!(f, "failed to read file size (errno {e})"),
+            MapError::Mmap(e) => write!(f, "failed to map file (errno {e})"),
+            MapError::Flush(e) => write!(f, "failed to flush mapping (errno {e})"),
+            MapError::Unmap(e) => write!(f, "failed to unmap file (errno {e})"),
+            MapError::Rename(e) => write!(f, "failed to replace file (errno {e})"),
+            MapError::Permissions(e) => {
+                write!(f, "failed to copy file permissions and owner (errno {e})")
             }
+            MapError::Lock(e) => write!(f, "failed to lock file (errno {e})"),
+            MapError::Layout(e) => e.fmt(f),
+            MapError::Header(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapError::Layout(e) => Some(e),
+            MapError::Header(e) => Some(e),
+            _ => None,
         }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<MapError> for std::io::Error {
+    fn from(e: MapError) -> std::io::Error {
+        match e {
+            MapError::Open(errno)
+            | MapError::Truncate(errno)
+            | MapError::Seek(errno)
+            | MapError::Mmap(errno)
+            | MapError::Flush(errno)
+            | MapError::Unmap(errno)
+            | MapError::Rename(errno)
+            | MapError::Permissions(errno)
+            | MapError::Lock(errno) => std::io::Error::from_raw_os_error(errno),
+            MapError::Layout(e) => e.into(),
+            MapError::Header(e) => e.into(),
+        }
+    }
+}
+
+/// Reads `errno` of the calling thread, only meaningful right after a failed call.
+fn errno() -> c_int {
+    unsafe { *errno_location() }
+}
+
+/// [`MappingBackend`] calling `open`/`mmap` directly, usable without std or an allocator.
+///
+/// This is the [`DefaultBackend`](crate::DefaultBackend) when only the `no_std` feature is enabled.
+#[derive(Debug)]
+pub struct LibcBackend {
+    // start and length of the whole mapping, `delta` bytes before the mapped data
+    raw: *mut c_void,
+    len: usize,
+    delta: usize,
+    write: bool,
+}
+
+// a plain region of shared memory, synchronizing access is up to the wrappers
+unsafe impl Send for LibcBackend {}
+unsafe impl Sync for LibcBackend {}
+
+impl LibcBackend {
+    fn map_fd(fd: &Fd, offset: usize, len: usize, write: bool) -> Result<LibcBackend, MapError> {
+        if len == 0 {
+            // mmap refuses empty mappings, hand out a dangling page aligned pointer instead
+            return Ok(LibcBackend {
+                raw: ptr::without_provenance_mut(EMPTY_ADDR),
+                len,
+                delta: 0,
+                write,
+            });
+        }
+
+        // mmap offsets must be page aligned, map from the start of the page and skip the rest
+        let page = unsafe { getpagesize() } as usize;
+        let delta = offset % page;
+        let len = len.checked_add(delta).ok_or(LayoutError::Overflow)?;
+        let Ok(page_offset) = off64_t::try_from(offset - delta) else {
+            return Err(MapError::Mmap(EOVERFLOW));
+        };
 
         let mmap_prot = if write {
             PROT_READ | PROT_WRITE
         } else {
             PROT_READ
         };
-        let mapped_region = unsafe {
-            mmap(
+        let raw = unsafe {
+            mmap64(
                 ptr::null_mut(),
-                size_of::<T>(),
+                len,
                 mmap_prot,
                 MAP_SHARED,
-                fd,
-                0,
+                fd.0,
+                page_offset,
             )
         };
+        if raw == MAP_FAILED {
+            return Err(MapError::Mmap(errno()));
+        }
+
+        Ok(LibcBackend {
+            raw,
+            len,
+            delta,
+            write,
+        })
+    }
 
-        if mapped_region == MAP_FAILED {
-            unsafe { close(fd) };
-            return Err(-1);
+    /// Writes the file that [`MappingBackend::replace`] renames over `path` to `tmp`.
+    fn write_replacement(
+        path: &CStr,
+        tmp: &CStr,
+        tmp_options: &OpenOptions,
+        len: usize,
+        fill: impl FnOnce(&LibcBackend) -> Result<(), MapError>,
+    ) -> Result<(), MapError> {
+        let mut old = mem::MaybeUninit::<Statx>::uninit();
+        let mask = STATX_MODE | STATX_UID | STATX_GID;
+        if unsafe { statx(AT_FDCWD, path.as_ptr(), 0, mask, old.as_mut_ptr()) } < 0 {
+            return Err(MapError::Permissions(errno()));
         }
+        let old = unsafe { old.assume_init() };
+
+        let fd = <Self as ResizableBackend>::open(tmp, tmp_options, true)?;
+        Self::set_file_len(&fd, len)?;
+
+        let new = Self::map_file(&fd, len)?;
+        fill(&new)?;
+        new.flush()?;
+
+        // the mode of `tmp_options` could widen a private file, keep what it had
+        if unsafe { fchmod(fd.0, (old.mode & 0o7777) as c_uint) } < 0
+            || unsafe { fchown(fd.0, old.uid, old.gid) } < 0
+        {
+            return Err(MapError::Permissions(errno()));
+        }
+
+        Ok(())
+    }
 
-        unsafe { close(fd) };
+    fn open_flags(options: &OpenOptions, write: bool) -> c_int {
+        let mut flags = if write { O_RDWR } else { O_RDONLY };
 
-        Ok(mapped_region)
+        if options.create_new {
+            flags |= O_CREAT | O_EXCL;
+        } else if options.create {
+            flags |= O_CREAT;
+        }
+        if options.cloexec {
+            flags |= O_CLOEXEC;
+        }
+        if options.nofollow {
+            flags |= O_NOFOLLOW;
+        }
+
+        flags
     }
+}
+
+unsafe impl MappingBackend for LibcBackend {
+    type Path = CStr;
+    type Error = MapError;
 
-    /// Retrieves a reference to the inner value of type `T` from the mapped memory.
+    /// Maps a file to memory.
     ///
     /// # Safety
     ///
-    /// - The function assumes that `self.raw` is a valid region aligned to `size_of::<T>()`.
-    /// - The caller must ensure that `T` has a consistent layout by using `#[repr(transparent)]` or `#[repr(C)]`.
+    /// - If resizing fails, the file descriptor is closed, and an error is returned.
+    /// - Memory mapping is done with either (`PROT_READ | PROT_WRITE` or `PROT_READ`) and `MAP_SHARED`. If the mapping fails, the file descriptor is closed, and an error is returned.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// This function is `unsafe` and does not perform any checks, so it may lead to undefined behavior if the safety guarantees are not met.
-    pub fn new(path: &CStr) -> Result<MmapWrapper<T>, c_int> {
-        Ok(MmapWrapper {
-            raw: Self::map(path, false)?,
-            _inner: PhantomData,
-        })
+    /// - Returns the [`MapError`] variant of the step that failed along with its `errno`.
+    /// - Returns `Err(MapError::Layout(..))` if the file is shorter than `offset + len`.
+    fn map(
+        path: &CStr,
+        options: &OpenOptions,
+        len: Option<usize>,
+        write: bool,
+    ) -> Result<LibcBackend, MapError> {
+        let fd = <Self as ResizableBackend>::open(path, options, write)?;
+
+        // mapping past the end of the file is fine for mmap
+        // but touching those pages later raises SIGBUS
+        let mut file_len = Self::file_len(&fd)?;
+
+        let offset = options.offset;
+        let required = match len {
+            Some(len) => offset.checked_add(len).ok_or(LayoutError::Overflow)?,
+            None => file_len.max(offset),
+        };
+        if write
+            && (options.truncate && file_len != required || options.grow && file_len < required)
+        {
+            Self::set_file_len(&fd, required)?;
+            file_len = required;
+        }
+
+        if file_len < required {
+            return Err(MapError::Layout(LayoutError::TooSmall {
+                len: file_len,
+                required,
+            }));
+        }
+
+        Self::map_fd(&fd, offset, required - offset, write)
     }
 
-    pub fn get_inner<'a>(&self) -> &'a T {
-        unsafe { &*self.raw.cast::<T>() }
+    fn replace(
+        path: &CStr,
+        options: &OpenOptions,
+        len: usize,
+        fill: impl FnOnce(&LibcBackend) -> Result<(), MapError>,
+    ) -> Result<(), MapError> {
+        const SUFFIX: &[u8] = b".tmp";
+
+        // no allocator, build `<path>.tmp` on the stack
+        let mut buf = [0u8; PATH_MAX];
+        let name = path.to_bytes();
+        if name.len() + SUFFIX.len() >= PATH_MAX {
+            return Err(MapError::Open(ENAMETOOLONG));
+        }
+        buf[..name.len()].copy_from_slice(name);
+        buf[name.len()..name.len() + SUFFIX.len()].copy_from_slice(SUFFIX);
+        let tmp = CStr::from_bytes_until_nul(&buf).expect("buffer ends with a nul byte");
+
+        // left over files of an earlier attempt are overwritten
+        let mut tmp_options = options.clone();
+        tmp_options
+            .create(true)
+            .create_new(false)
+            .truncate(true)
+            .offset(0);
+
+        if let Err(e) = Self::write_replacement(path, tmp, &tmp_options, len, fill) {
+            unsafe { unlink(tmp.as_ptr()) };
+            return Err(e);
+        }
+
+        if unsafe { rename(tmp.as_ptr(), path.as_ptr()) } < 0 {
+            return Err(MapError::Rename(errno()));
+        }
+
+        Ok(())
     }
-}
 
-impl<T> Clone for MmapMutWrapper<T> {
-    fn clone(&self) -> Self {
-        // this is horrifying
-        MmapMutWrapper {
-            raw: unsafe { transmute_copy(&self.raw) },
-            _inner: PhantomData,
+    fn map_anon(len: usize) -> Result<LibcBackend, MapError> {
+        let raw = unsafe {
+            mmap64(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if raw == MAP_FAILED {
+            return Err(MapError::Mmap(errno()));
         }
+
+        Ok(LibcBackend {
+            raw,
+            len,
+            delta: 0,
+            write: true,
+        })
     }
-}
 
-impl<T> Clone for MmapWrapper<T> {
-    fn clone(&self) -> Self {
-        // this is horrifying
-        MmapWrapper {
-            raw: unsafe { transmute_copy(&self.raw) },
-            _inner: PhantomData,
+    fn unmap(self) -> Result<(), MapError> {
+        if self.len == 0 {
+            mem::forget(self);
+            return Ok(());
         }
+
+        let res = unsafe { munmap(self.raw, self.len) };
+        mem::forget(self);
+
+        if res < 0 {
+            return Err(MapError::Unmap(errno()));
+        }
+
+        Ok(())
     }
-}
 
-impl<T> MmapMutWrapper<T> {
-    /// Retrieves a mutable reference to the inner value of type `T` from the mapped memory.
-    ///
-    /// # Safety
-    ///
-    /// - The function assumes that `self.raw` is a valid region aligned to `size_of::<T>()`.
-    /// - The caller must ensure that `T` has a consistent layout by using `#[repr(transparent)]` or `#[repr(C)]`.
-    ///
-    /// # Panics
-    ///
-    /// This function is `unsafe` and does not perform any checks, so it may lead to undefined behavior if the safety guarantees are not met.
-    pub unsafe fn new(path: &CStr) -> Result<MmapMutWrapper<T>, c_int> {
-        Ok(MmapMutWrapper {
-            raw: MmapWrapper::<T>::map(path, true)?,
-            _inner: PhantomData,
-        })
+    fn flush(&self) -> Result<(), MapError> {
+        if self.len > 0 && unsafe { msync(self.raw, self.len, MS_SYNC) } < 0 {
+            return Err(MapError::Flush(errno()));
+        }
+
+        Ok(())
+    }
+
+    fn writable(&self) -> bool {
+        self.write
+    }
+
+    fn len(&self) -> usize {
+        self.len - self.delta
     }
 
-    pub fn get_inner<'a>(&self) -> &'a mut T {
-        unsafe { &mut *self.raw.cast::<T>() }
+    fn as_ptr(&self) -> *mut u8 {
+        self.raw.cast::<u8>().wrapping_add(self.delta)
+    }
+}
+
+/// An open file descriptor, closed when dropped.
+#[derive(Debug)]
+pub struct Fd(c_int);
+
+impl Fd {
+    /// The raw descriptor, still owned by this value.
+    pub fn as_raw(&self) -> c_int {
+        self.0
     }
 }
 
-impl<T> Drop for MmapWrapper<T> {
+impl Drop for Fd {
     fn drop(&mut self) {
-        if !self.raw.is_null() {
-            unsafe {
-                munmap(self.raw, size_of::<T>());
-            }
+        unsafe { close(self.0) };
+    }
+}
+
+unsafe impl ResizableBackend for LibcBackend {
+    type File = Fd;
+
+    fn open(path: &CStr, options: &OpenOptions, write: bool) -> Result<Fd, MapError> {
+        let fd = unsafe {
+            open(
+                path.as_ptr(),
+                Self::open_flags(options, write),
+                options.mode as c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(MapError::Open(errno()));
         }
+
+        Ok(Fd(fd))
+    }
+
+    fn file_len(fd: &Fd) -> Result<usize, MapError> {
+        let len = unsafe { lseek64(fd.0, 0, SEEK_END) };
+        if len < 0 {
+            return Err(MapError::Seek(errno()));
+        }
+
+        // files past 4 GiB can't be mapped whole on 32-bit targets
+        usize::try_from(len).map_err(|_| MapError::Seek(EOVERFLOW))
+    }
+
+    fn set_file_len(fd: &Fd, len: usize) -> Result<(), MapError> {
+        let Ok(len) = off64_t::try_from(len) else {
+            return Err(MapError::Truncate(EFBIG));
+        };
+        if unsafe { ftruncate64(fd.0, len) } < 0 {
+            return Err(MapError::Truncate(errno()));
+        }
+
+        Ok(())
+    }
+
+    fn try_lock(fd: &Fd) -> Result<(), MapError> {
+        if unsafe { flock(fd.0, LOCK_EX | LOCK_NB) } < 0 {
+            return Err(MapError::Lock(errno()));
+        }
+
+        Ok(())
+    }
+
+    fn map_file(fd: &Fd, len: usize) -> Result<LibcBackend, MapError> {
+        Self::map_fd(fd, 0, len, true)
+    }
+
+    fn remap(&mut self, fd: &Fd, len: usize) -> Result<(), MapError> {
+        if self.len == 0 || len == 0 {
+            *self = Self::map_fd(fd, 0, len, true)?;
+            return Ok(());
+        }
+
+        let len = len + self.delta;
+        let raw = unsafe { mremap(self.raw, self.len, len, MREMAP_MAYMOVE) };
+        if raw == MAP_FAILED {
+            return Err(MapError::Mmap(errno()));
+        }
+
+        self.raw = raw;
+        self.len = len;
+        Ok(())
     }
 }
 
-impl<T> Drop for MmapMutWrapper<T> {
+impl Drop for LibcBackend {
     fn drop(&mut self) {
-        if !self.raw.is_null() {
+        if self.len > 0 {
             unsafe {
-                munmap(self.raw, size_of::<T>());
+                munmap(self.raw, self.len);
             }
         }
     }
 }
 
-#[cfg(test)]
+impl<T: MmapSafe> MmapWrapper<T, LibcBackend> {
+    /// Maps an existing file at `path` read-only.
+    ///
+    /// Use [`OpenOptions`] for more control over how the file is opened.
+    pub fn new(path: &CStr) -> Result<MmapWrapper<T, LibcBackend>, MapError> {
+        Self::try_new(path)
+    }
+
+    /// Same as [`MmapWrapper::new`] but reports why the file could not
+    /// be mapped, including when it is too short or misaligned for `T`.
+    pub fn try_new(path: &CStr) -> Result<MmapWrapper<T, LibcBackend>, MapError> {
+        Self::open(path)
+    }
+}
+
+impl<T: MmapSafe> MmapMutWrapper<T, LibcBackend> {
+    /// Maps the file at `path` read-write, creating it if necessary
+    /// and truncating it to the size of `T`.
+    ///
+    /// Use [`OpenOptions`] to keep trailing data or avoid creating the file.
+    pub fn new(path: &CStr) -> Result<MmapMutWrapper<T, LibcBackend>, MapError> {
+        Self::try_new(path)
+    }
+
+    /// Same as [`MmapMutWrapper::new`] but reports why the file could not
+    /// be mapped, including when it is misaligned for `T`.
+    pub fn try_new(path: &CStr) -> Result<MmapMutWrapper<T, LibcBackend>, MapError> {
+        Self::create(path)
+    }
+}
+
+#[cfg(all(test, feature = "derive"))]
 mod tests {
+    extern crate std;
+
     use core::ffi::CStr;
 
-    use crate::{MmapMutWrapper, MmapWrapper};
+    use super::LibcBackend;
+    use crate::{LayoutError, MapError, MappingBackend, MmapSafe, OpenOptions};
 
+    type MmapWrapper<T> = crate::MmapWrapper<T, LibcBackend>;
+    type MmapMutWrapper<T> = crate::MmapMutWrapper<T, LibcBackend>;
+
+    #[derive(MmapSafe)]
     #[repr(C)]
     struct MyStruct {
-        thing1: i32,
+        thing1: i64,
         thing2: f64,
     }
 
@@ -249,24 +647,183 @@ mod tests {
     fn basic_rw() {
         const PATH: &CStr = c"/tmp/mmap-wrapper-test";
 
-        let rw_wrapper = MmapMutWrapper::<MyStruct>::new(PATH).unwrap();
+        let mut rw_wrapper = MmapMutWrapper::<MyStruct>::new(PATH).unwrap();
 
-        let mut_inner = unsafe { rw_wrapper.get_inner() };
-        mut_inner.thing1 = i32::MAX;
+        let mut mut_inner = rw_wrapper.write();
+        mut_inner.thing1 = i64::MAX;
         mut_inner.thing2 = f64::MIN;
 
         let ro_wrapper = MmapWrapper::<MyStruct>::new(PATH).unwrap();
-        let inner = unsafe { ro_wrapper.get_inner() };
+        let inner = ro_wrapper.read();
 
-        assert_eq!(inner.thing1, i32::MAX);
+        assert_eq!(inner.thing1, i64::MAX);
         assert_eq!(inner.thing2, f64::MIN);
 
         drop(ro_wrapper);
 
-        mut_inner.thing1 = i32::MIN;
+        mut_inner.thing1 = i64::MIN;
         mut_inner.thing2 = f64::MAX;
 
-        assert_eq!(mut_inner.thing1, i32::MIN);
+        assert_eq!(mut_inner.thing1, i64::MIN);
         assert_eq!(mut_inner.thing2, f64::MAX);
     }
+
+    #[test]
+    fn write_guard_is_exclusive() {
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-guards";
+
+        let mut rw_wrapper = MmapMutWrapper::<MyStruct>::new(PATH).unwrap();
+        let rw_clone = rw_wrapper.clone();
+
+        let mut writer = rw_wrapper.write();
+        writer.thing1 = 3;
+        assert!(rw_clone.try_read().is_err());
+        drop(writer);
+
+        assert_eq!(rw_clone.read().thing1, 3);
+    }
+
+    #[test]
+    fn last_clone_unmaps() {
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-refcount";
+
+        let mut rw_wrapper = MmapMutWrapper::<MyStruct>::new(PATH).unwrap();
+        rw_wrapper.write().thing1 = 5;
+
+        let mut rw_clone = rw_wrapper.clone();
+        drop(rw_wrapper);
+
+        let t = std::thread::spawn(move || {
+            rw_clone.write().thing1 += 1;
+            rw_clone
+        });
+        let rw_clone = t.join().unwrap();
+
+        assert_eq!(rw_clone.read().thing1, 6);
+    }
+
+    #[test]
+    fn map_error_errno() {
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-missing-dir/file";
+        const ENOENT: core::ffi::c_int = 2;
+
+        let res = MmapWrapper::<MyStruct>::try_new(PATH);
+        assert_eq!(res.err(), Some(MapError::Open(ENOENT)));
+
+        #[cfg(feature = "std")]
+        {
+            let e: std::io::Error = MapError::Open(ENOENT).into();
+            assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        }
+    }
+
+    #[test]
+    fn open_options() {
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-options";
+        const EEXIST: core::ffi::c_int = 17;
+
+        let path = PATH.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        // read-only opens don't create files
+        let res = MmapWrapper::<MyStruct>::new(PATH);
+        assert_eq!(res.err(), Some(MapError::Open(2)));
+
+        let mut w = MmapMutWrapper::<MyStruct>::open_with(
+            PATH,
+            OpenOptions::new().create_new(true).grow(true),
+        )
+        .unwrap();
+        w.write().thing1 = 9;
+
+        let res = MmapMutWrapper::<MyStruct>::open_with(PATH, OpenOptions::new().create_new(true));
+        assert_eq!(res.err(), Some(MapError::Open(EEXIST)));
+
+        // grow leaves trailing data alone
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(4096)
+            .unwrap();
+        let w = MmapMutWrapper::<MyStruct>::open_with(PATH, OpenOptions::new().grow(true)).unwrap();
+        assert_eq!(w.read().thing1, 9);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn read_only_is_not_mutable() {
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-read-only-libc";
+
+        MmapMutWrapper::<MyStruct>::new(PATH).unwrap();
+        let backend = LibcBackend::map(PATH, &OpenOptions::new(), None, false).unwrap();
+        assert_eq!(
+            MmapMutWrapper::<MyStruct>::from_backend(backend).err(),
+            Some(MapError::Layout(LayoutError::ReadOnly))
+        );
+    }
+
+    #[test]
+    fn replace_renames_over() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-replace";
+
+        let path = PATH.to_str().unwrap();
+        std::fs::write(path, [1u8; 8]).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        // a failed fill leaves the old file and no temporary one
+        let res = LibcBackend::replace(PATH, &OpenOptions::new(), 16, |_| Err(MapError::Flush(5)));
+        assert_eq!(res, Err(MapError::Flush(5)));
+        assert_eq!(std::fs::read(path).unwrap(), [1u8; 8]);
+        assert!(!std::path::Path::new("/tmp/mmap-wrapper-test-replace.tmp").exists());
+
+        LibcBackend::replace(PATH, &OpenOptions::new(), 16, |new| {
+            unsafe { new.as_ptr().write_bytes(2, new.len()) };
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), [2u8; 16]);
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn large_offsets() {
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-large-offsets";
+        const EFBIG: core::ffi::c_int = 27;
+
+        MmapMutWrapper::<MyStruct>::new(PATH).unwrap();
+
+        assert_eq!(
+            MmapWrapper::<MyStruct>::open_at(PATH, usize::MAX - 8).err(),
+            Some(MapError::Layout(LayoutError::Overflow))
+        );
+        // fits a usize but not the signed 64-bit offsets of the kernel
+        assert_eq!(
+            MmapMutWrapper::<MyStruct>::open_with(
+                PATH,
+                OpenOptions::new().grow(true).offset(1 << 63)
+            )
+            .err(),
+            Some(MapError::Truncate(EFBIG))
+        );
+    }
+
+    #[test]
+    fn try_new_too_small() {
+        const PATH: &CStr = c"/tmp/mmap-wrapper-test-too-small";
+
+        std::fs::File::create(PATH.to_str().unwrap()).unwrap();
+        let res = MmapWrapper::<MyStruct>::try_new(PATH);
+
+        assert_eq!(
+            res.err(),
+            Some(MapError::Layout(LayoutError::TooSmall {
+                len: 0,
+                required: size_of::<MyStruct>()
+            }))
+        );
+    }
 }
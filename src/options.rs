@@ -0,0 +1,169 @@
+use core::mem::size_of;
+
+use crate::{DefaultBackend, FileHeader, MappingBackend, MmapMutWrapper, MmapSafe, MmapWrapper};
+
+/// Options for opening and mapping a file, like [`std::fs::OpenOptions`].
+///
+/// Nothing is created or resized unless asked for, which makes it safe to use on
+/// files owned by other programs.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapSafe, OpenOptions};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct MyStruct {
+///    thing1: i64,
+///    thing2: f64,
+/// }
+///
+/// let m_wrapper = OpenOptions::new()
+///     .create(true)
+///     .grow(true)
+///     .mode(0o600)
+///     .map_mut::<MyStruct>("/tmp/mystruct-mmap-options-test.bin")
+///     .unwrap();
+/// # }
+/// ```
+///
+/// [`std::fs::OpenOptions`]: https://doc.rust-lang.org/std/fs/struct.OpenOptions.html
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    pub(crate) create: bool,
+    pub(crate) create_new: bool,
+    pub(crate) truncate: bool,
+    pub(crate) grow: bool,
+    pub(crate) mode: u32,
+    pub(crate) cloexec: bool,
+    pub(crate) nofollow: bool,
+    pub(crate) header: Option<u32>,
+    pub(crate) offset: usize,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptions {
+    /// Opens existing files only, without resizing them, with `O_CLOEXEC` set.
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            create: false,
+            create_new: false,
+            truncate: false,
+            grow: false,
+            mode: 0o644,
+            cloexec: true,
+            nofollow: false,
+            header: None,
+            offset: 0,
+        }
+    }
+
+    /// Creates the file if it does not exist (`O_CREAT`).
+    ///
+    /// The std backend only allows this for writable mappings.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Creates the file, failing if it already exists (`O_CREAT | O_EXCL`).
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Resizes the file to exactly the mapped length, dropping any trailing data.
+    ///
+    /// Only applies to writable mappings and takes precedence over [`OpenOptions::grow`].
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Extends the file to the mapped length if it is shorter, larger files are left untouched.
+    ///
+    /// Only applies to writable mappings.
+    pub fn grow(&mut self, grow: bool) -> &mut Self {
+        self.grow = grow;
+        self
+    }
+
+    /// Permission bits used when the file is created, `0o644` by default.
+    ///
+    /// Ignored on non-unix platforms.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Closes the descriptor on `exec` (`O_CLOEXEC`), on by default.
+    ///
+    /// The std backend always sets it.
+    pub fn cloexec(&mut self, cloexec: bool) -> &mut Self {
+        self.cloexec = cloexec;
+        self
+    }
+
+    /// Refuses to follow a symlink in the last path component (`O_NOFOLLOW`).
+    ///
+    /// Ignored on non-unix platforms.
+    pub fn nofollow(&mut self, nofollow: bool) -> &mut Self {
+        self.nofollow = nofollow;
+        self
+    }
+
+    /// Stores a [`FileHeader`] with schema `version` in front of the data.
+    ///
+    /// Writable mappings write the header into a new file too short to hold one, every other
+    /// open checks it against `T` and `version` and fails with a [`HeaderError`] on any mismatch.
+    ///
+    /// [`HeaderError`]: crate::HeaderError
+    pub fn header(&mut self, version: u32) -> &mut Self {
+        self.header = Some(version);
+        self
+    }
+
+    /// Maps the file starting `offset` bytes in instead of at the beginning.
+    ///
+    /// The offset does not need to be page aligned, the wrapped value starts exactly at `offset`
+    /// but must still be aligned for its type. Creating, truncating and growing the file apply
+    /// to the end of the mapped value.
+    pub fn offset(&mut self, offset: usize) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Bytes needed to map a `T`, including the header if there is one.
+    pub(crate) fn map_len<T>(&self) -> usize {
+        match self.header {
+            Some(_) => FileHeader::data_offset::<T>() + size_of::<T>(),
+            None => size_of::<T>(),
+        }
+    }
+
+    /// Maps the file at `path` read-only with the [`DefaultBackend`].
+    ///
+    /// See [`MmapWrapper::open_with`] to pick another backend.
+    pub fn map<T: MmapSafe>(
+        &self,
+        path: impl AsRef<<DefaultBackend as MappingBackend>::Path>,
+    ) -> Result<MmapWrapper<T>, <DefaultBackend as MappingBackend>::Error> {
+        MmapWrapper::open_with(path, self)
+    }
+
+    /// Maps the file at `path` read-write with the [`DefaultBackend`].
+    ///
+    /// See [`MmapMutWrapper::open_with`] to pick another backend.
+    pub fn map_mut<T: MmapSafe>(
+        &self,
+        path: impl AsRef<<DefaultBackend as MappingBackend>::Path>,
+    ) -> Result<MmapMutWrapper<T>, <DefaultBackend as MappingBackend>::Error> {
+        MmapMutWrapper::open_with(path, self)
+    }
+}
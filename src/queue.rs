@@ -0,0 +1,263 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ring::{CachePadded, SlotLayout};
+use crate::{DefaultBackend, LayoutHasher, MappingBackend, MmapHeaderSlice, MmapSafe, OpenOptions};
+
+#[repr(C)]
+pub(crate) struct QueueHeader {
+    layout: SlotLayout,
+    enqueue: CachePadded<AtomicU64>,
+    dequeue: CachePadded<AtomicU64>,
+}
+
+unsafe impl MmapSafe for QueueHeader {}
+
+/// A slot is free for the enqueue at position `pos` when `seq == pos`
+/// and holds a value for the dequeue at `pos` when `seq == pos + 1`.
+#[repr(C)]
+pub(crate) struct Slot<T> {
+    seq: AtomicU64,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: MmapSafe> MmapSafe for Slot<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("Slot")
+        .write_u64(T::LAYOUT_HASH)
+        .finish();
+}
+
+/// A bounded multi-producer multi-consumer queue of `T` stored in a memory-mapped file.
+///
+/// Any number of threads and processes can push and pop through `&self`, each slot carries
+/// a sequence number so no lock is needed (Dmitry Vyukov's bounded queue).
+/// The header records the capacity and element layout, openers expecting anything else are rejected.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use mmap_wrapper::MmapMpmcQueue;
+///
+/// let queue = MmapMpmcQueue::<u64>::create("/tmp/queue-mmap-test.bin", 2).unwrap();
+/// let other = MmapMpmcQueue::<u64>::open("/tmp/queue-mmap-test.bin").unwrap();
+///
+/// assert!(queue.push(1).is_ok());
+/// assert!(other.push(2).is_ok());
+/// assert_eq!(queue.push(3), Err(3));
+///
+/// assert_eq!(other.pop(), Some(1));
+/// assert_eq!(queue.pop(), Some(2));
+/// assert_eq!(queue.pop(), None);
+/// # }
+/// ```
+pub struct MmapMpmcQueue<T, B: MappingBackend = DefaultBackend> {
+    map: MmapHeaderSlice<QueueHeader, Slot<T>, B>,
+}
+
+// values only move between threads through the slot sequence numbers
+unsafe impl<T: Send, B: MappingBackend + Send> Send for MmapMpmcQueue<T, B> {}
+unsafe impl<T: Send, B: MappingBackend + Sync> Sync for MmapMpmcQueue<T, B> {}
+
+impl<T: MmapSafe, B: MappingBackend> MmapMpmcQueue<T, B> {
+    /// Creates a queue of `capacity` slots at `path`, replacing any existing file contents.
+    pub fn create(
+        path: impl AsRef<B::Path>,
+        capacity: usize,
+    ) -> Result<MmapMpmcQueue<T, B>, B::Error> {
+        Self::create_with(
+            path,
+            OpenOptions::new().create(true).truncate(true),
+            capacity,
+        )
+    }
+
+    /// Creates a queue of `capacity` slots at `path` opened according to `options`,
+    /// replacing any existing queue.
+    pub fn create_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+        capacity: usize,
+    ) -> Result<MmapMpmcQueue<T, B>, B::Error> {
+        assert!(capacity > 0, "queue capacity must not be zero");
+
+        let mut map =
+            MmapHeaderSlice::<QueueHeader, Slot<T>, B>::open_with_len(path, options, capacity)?;
+        let (header, slots) = map.split_mut();
+        for (i, slot) in slots.iter_mut().enumerate() {
+            slot.seq.store(i as u64, Ordering::Relaxed);
+        }
+        header.enqueue.value.store(0, Ordering::Relaxed);
+        header.dequeue.value.store(0, Ordering::Relaxed);
+        header.layout.write::<T>(capacity);
+
+        Ok(MmapMpmcQueue { map })
+    }
+
+    /// Opens the queue created at `path`, checking its capacity and that it holds `T`s.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapMpmcQueue<T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Opens the queue created at `path` according to `options`,
+    /// checking its capacity and that it holds `T`s.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapMpmcQueue<T, B>, B::Error> {
+        let map = MmapHeaderSlice::<QueueHeader, Slot<T>, B>::open_with(path, options)?;
+        map.header().layout.check::<T>(map.items().len())?;
+
+        Ok(MmapMpmcQueue { map })
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.map.items().len()
+    }
+
+    fn slot(&self, pos: u64) -> &Slot<T> {
+        let slots = self.map.items();
+        &slots[(pos % slots.len() as u64) as usize]
+    }
+
+    /// Appends `value`, handing it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let enqueue = &self.map.header().enqueue.value;
+        let mut pos = enqueue.load(Ordering::Relaxed);
+
+        loop {
+            let slot = self.slot(pos);
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as i64 - pos as i64;
+
+            if diff == 0 {
+                match enqueue.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { slot.value.get().write(value) };
+                        slot.seq.store(pos + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if diff < 0 {
+                // the slot still holds the value from a lap ago
+                return Err(value);
+            } else {
+                pos = enqueue.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes the oldest value, `None` if the queue is empty.
+    pub fn pop(&self) -> Option<T> {
+        let dequeue = &self.map.header().dequeue.value;
+        let mut pos = dequeue.load(Ordering::Relaxed);
+
+        loop {
+            let slot = self.slot(pos);
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as i64 - (pos + 1) as i64;
+
+            if diff == 0 {
+                match dequeue.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { slot.value.get().read() };
+                        // free the slot for the push one lap ahead
+                        slot.seq
+                            .store(pos + self.capacity() as u64, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if diff < 0 {
+                // nothing has been pushed into this slot yet
+                return None;
+            } else {
+                pos = dequeue.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{HeaderError, MmapMpmcQueue};
+
+    #[test]
+    fn mpmc_many_threads() {
+        let path = "/tmp/mmap-wrapper-test-mpmc";
+        let _ = std::fs::remove_file(path);
+        MmapMpmcQueue::<u64>::create(path, 100).unwrap();
+
+        let producers: Vec<_> = (0..4)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    let queue = MmapMpmcQueue::<u64>::open(path).unwrap();
+                    for i in 0..1000 {
+                        while queue.push(t * 1000 + i).is_err() {
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let queue = MmapMpmcQueue::<u64>::open(path).unwrap();
+                    let mut sum = 0;
+                    for _ in 0..1000 {
+                        sum += loop {
+                            match queue.pop() {
+                                Some(v) => break v,
+                                None => std::thread::yield_now(),
+                            }
+                        };
+                    }
+                    sum
+                })
+            })
+            .collect();
+
+        producers.into_iter().for_each(|p| p.join().unwrap());
+        let sum: u64 = consumers.into_iter().map(|c| c.join().unwrap()).sum();
+        assert_eq!(sum, 4000 * 3999 / 2);
+    }
+
+    #[test]
+    fn mpmc_rejects_mismatch() {
+        let path = "/tmp/mmap-wrapper-test-mpmc-mismatch";
+        let _ = std::fs::remove_file(path);
+        MmapMpmcQueue::<u64>::create(path, 4).unwrap();
+
+        let e = MmapMpmcQueue::<[u32; 2]>::open(path).err().unwrap();
+        assert!(matches!(
+            *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+            HeaderError::Layout { .. }
+        ));
+
+        // chop off a slot, the header still says 4
+        let f = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 16).unwrap();
+        let e = MmapMpmcQueue::<u64>::open(path).err().unwrap();
+        assert_eq!(
+            *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+            HeaderError::Capacity {
+                found: 4,
+                expected: 3
+            }
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::slice;
+
+use crate::{LayoutError, LayoutHasher, MmapSafe};
+
+/// A pointer to a `T` stored as a byte offset from the start of a mapping.
+///
+/// Unlike a real pointer it means the same thing in every process that maps the file,
+/// so mapped structs can link to each other to build lists, trees and string tables.
+/// A zeroed `RelPtr` is null.
+///
+/// Resolving checks that the target lies inside the mapping and is aligned for `T`.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapSafe, MmapSliceMutWrapper, RelPtr};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct Node {
+///    value: u64,
+///    next: RelPtr<Node>,
+/// }
+///
+/// let mut heap = MmapSliceMutWrapper::<u8>::create("/tmp/list-mmap-test.bin", 256).unwrap();
+/// let mut bytes = heap.write();
+///
+/// // two nodes linked back to front
+/// let (first, second) = (RelPtr::<Node>::new(0), RelPtr::<Node>::new(16));
+/// *second.resolve_mut(&mut bytes).unwrap() = Node { value: 2, next: RelPtr::null() };
+/// *first.resolve_mut(&mut bytes).unwrap() = Node { value: 1, next: second };
+///
+/// let mut sum = 0;
+/// let mut node = first;
+/// while let Ok(n) = node.resolve(&bytes) {
+///     sum += n.value;
+///     node = n.next;
+/// }
+/// assert_eq!(sum, 3);
+/// # }
+/// ```
+#[repr(transparent)]
+pub struct RelPtr<T> {
+    // target offset plus one, so zero is null
+    offset: u64,
+    _target: PhantomData<fn() -> T>,
+}
+
+// doesn't depend on `T` so self-referential structs have a layout hash
+unsafe impl<T> MmapSafe for RelPtr<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new().write_str("RelPtr").finish();
+}
+
+impl<T> RelPtr<T> {
+    /// A pointer that doesn't point anywhere.
+    pub const fn null() -> RelPtr<T> {
+        RelPtr {
+            offset: 0,
+            _target: PhantomData,
+        }
+    }
+
+    /// A pointer to the `T` `offset` bytes from the start of the mapping.
+    ///
+    /// # Panics
+    /// Panics if `offset` is `u64::MAX`, which no mapping reaches and has no room left for null.
+    pub const fn new(offset: usize) -> RelPtr<T> {
+        let Some(offset) = (offset as u64).checked_add(1) else {
+            panic!("relative pointer offset out of range");
+        };
+
+        RelPtr {
+            offset,
+            _target: PhantomData,
+        }
+    }
+
+    /// A pointer to `target`, `None` if it doesn't lie inside `mapping`.
+    pub fn from_ref(mapping: &[u8], target: &T) -> Option<RelPtr<T>> {
+        let offset = (target as *const T as usize).checked_sub(mapping.as_ptr() as usize)?;
+        if offset + size_of::<T>() > mapping.len() {
+            return None;
+        }
+
+        Some(RelPtr::new(offset))
+    }
+
+    /// Returns `true` if the pointer doesn't point anywhere.
+    pub const fn is_null(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Offset of the target from the start of the mapping, `None` if the pointer is null.
+    pub const fn offset(&self) -> Option<usize> {
+        match self.offset {
+            0 => None,
+            offset => Some((offset - 1) as usize),
+        }
+    }
+
+    /// The same offset as a pointer to a `U`, e.g. to use memory from [`MmapHeap::alloc`].
+    ///
+    /// [`MmapHeap::alloc`]: crate::MmapHeap::alloc
+    pub const fn cast<U>(self) -> RelPtr<U> {
+        RelPtr {
+            offset: self.offset,
+            _target: PhantomData,
+        }
+    }
+
+    /// Checks that `len` `T`s fit in `mapping` at the target, returning where they start.
+    fn target(&self, mapping: &[u8], len: usize) -> Result<*const u8, LayoutError> {
+        let offset = self.offset().ok_or(LayoutError::Null)?;
+        let required = len
+            .checked_mul(size_of::<T>())
+            .and_then(|size| size.checked_add(offset))
+            .filter(|&end| end <= mapping.len())
+            .ok_or(LayoutError::TooSmall {
+                len: mapping.len(),
+                required: offset.saturating_add(len.saturating_mul(size_of::<T>())),
+            })?;
+
+        let target = mapping[offset..].as_ptr();
+        LayoutError::check_slice::<T>(target, required - offset)?;
+
+        Ok(target)
+    }
+
+    /// The `T` this points to inside `mapping`.
+    pub fn resolve<'a>(&self, mapping: &'a [u8]) -> Result<&'a T, LayoutError>
+    where
+        T: MmapSafe,
+    {
+        Ok(unsafe { &*self.target(mapping, 1)?.cast() })
+    }
+
+    /// The `T` this points to inside `mapping`, mutably.
+    pub fn resolve_mut<'a>(&self, mapping: &'a mut [u8]) -> Result<&'a mut T, LayoutError>
+    where
+        T: MmapSafe,
+    {
+        Ok(unsafe { &mut *self.target(mapping, 1)?.cast_mut().cast() })
+    }
+
+    /// The `len` `T`s starting at the target inside `mapping`, e.g. a string in a string table.
+    pub fn resolve_slice<'a>(&self, mapping: &'a [u8], len: usize) -> Result<&'a [T], LayoutError>
+    where
+        T: MmapSafe,
+    {
+        Ok(unsafe { slice::from_raw_parts(self.target(mapping, len)?.cast(), len) })
+    }
+}
+
+impl<T> Clone for RelPtr<T> {
+    fn clone(&self) -> RelPtr<T> {
+        *self
+    }
+}
+
+impl<T> Copy for RelPtr<T> {}
+
+impl<T> PartialEq for RelPtr<T> {
+    fn eq(&self, other: &RelPtr<T>) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> Eq for RelPtr<T> {}
+
+impl<T> Default for RelPtr<T> {
+    fn default() -> RelPtr<T> {
+        RelPtr::null()
+    }
+}
+
+impl<T> fmt::Debug for RelPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RelPtr").field(&self.offset()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::slice;
+
+    use crate::{LayoutError, RelPtr};
+
+    fn bytes(words: &mut [u64]) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(words.as_mut_ptr().cast(), words.len() * 8) }
+    }
+
+    #[test]
+    fn resolve_checks_bounds() {
+        let mut words = [0u64; 4];
+        let mapping = bytes(&mut words);
+
+        *RelPtr::<u64>::new(24).resolve_mut(mapping).unwrap() = 7;
+        assert_eq!(RelPtr::<u64>::new(24).resolve(mapping), Ok(&7));
+
+        assert_eq!(
+            RelPtr::<u64>::new(32).resolve(mapping),
+            Err(LayoutError::TooSmall {
+                len: 32,
+                required: 40
+            })
+        );
+        assert!(matches!(
+            RelPtr::<u64>::new(4).resolve(mapping),
+            Err(LayoutError::Misaligned { .. })
+        ));
+        assert_eq!(
+            RelPtr::<u64>::null().resolve(mapping),
+            Err(LayoutError::Null)
+        );
+        assert_eq!(
+            RelPtr::<u64>::new(usize::MAX - 1).resolve_slice(mapping, 2),
+            Err(LayoutError::TooSmall {
+                len: 32,
+                required: usize::MAX
+            })
+        );
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn last_offset() {
+        RelPtr::<u8>::new(usize::MAX);
+    }
+
+    #[test]
+    fn string_table() {
+        let mut words = [0u64; 4];
+        let mapping = bytes(&mut words);
+        mapping[5..10].copy_from_slice(b"hello");
+
+        let s = RelPtr::<u8>::from_ref(mapping, &mapping[5]).unwrap();
+        assert_eq!(s.offset(), Some(5));
+        assert_eq!(s.resolve_slice(mapping, 5).unwrap(), b"hello");
+        assert_eq!(RelPtr::<u8>::from_ref(&mapping[..4], &mapping[5]), None);
+        assert_eq!(RelPtr::<u8>::default(), RelPtr::null());
+    }
+}
@@ -0,0 +1,399 @@
+use core::cell::UnsafeCell;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    DefaultBackend, HeaderError, LayoutHasher, MappingBackend, MmapHeaderSlice, MmapSafe,
+    OpenOptions,
+};
+
+/// Keeps `value` on its own cache line so the producer and consumer don't invalidate each other.
+#[repr(C, align(64))]
+pub(crate) struct CachePadded<T> {
+    pub(crate) value: T,
+}
+
+// the crate's own mapped types implement it by hand so they build without the `derive` feature
+unsafe impl<T: MmapSafe> MmapSafe for CachePadded<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("CachePadded")
+        .write_u64(T::LAYOUT_HASH)
+        .finish();
+}
+
+/// Describes the slots following a shared memory header, checked by every opener.
+#[repr(C)]
+pub(crate) struct SlotLayout {
+    capacity: u64,
+    size: u64,
+    align: u64,
+    hash: u64,
+}
+
+unsafe impl MmapSafe for SlotLayout {}
+
+impl SlotLayout {
+    pub(crate) fn write<T: MmapSafe>(&mut self, capacity: usize) {
+        self.capacity = capacity as u64;
+        self.size = size_of::<T>() as u64;
+        self.align = align_of::<T>() as u64;
+        self.hash = T::LAYOUT_HASH;
+    }
+
+    /// Checks that the slots hold `T`s and that the file has room for all `capacity` of them.
+    pub(crate) fn check<T: MmapSafe>(&self, slots: usize) -> Result<(), HeaderError> {
+        if self.size != size_of::<T>() as u64 || self.align != align_of::<T>() as u64 {
+            return Err(HeaderError::Layout {
+                found: (self.size, self.align as u32),
+                expected: (size_of::<T>() as u64, align_of::<T>() as u32),
+            });
+        }
+        if self.hash != T::LAYOUT_HASH {
+            return Err(HeaderError::LayoutHash {
+                found: self.hash,
+                expected: T::LAYOUT_HASH,
+            });
+        }
+        if self.capacity == 0 || self.capacity != slots as u64 {
+            return Err(HeaderError::Capacity {
+                found: self.capacity,
+                expected: slots as u64,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[repr(C)]
+pub(crate) struct RingHeader {
+    layout: SlotLayout,
+    // next slot to pop, only written by the consumer
+    head: CachePadded<AtomicU64>,
+    // next slot to push, only written by the producer
+    tail: CachePadded<AtomicU64>,
+}
+
+unsafe impl MmapSafe for RingHeader {}
+
+/// A bounded single-producer single-consumer queue of `T` stored in a memory-mapped file.
+///
+/// The producer and consumer can live in different processes, each opening the file by path.
+/// Only one of them may push and only one may pop at any time, the `&mut self` methods
+/// enforce this for a single handle but not across handles or processes.
+///
+/// Opening checks that the stored indices are no further apart than the ring has slots. If the
+/// file is corrupted later on, the ring stops moving: pushes find it full and pops find it empty.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use mmap_wrapper::MmapSpscRing;
+///
+/// let mut producer = MmapSpscRing::<u64>::create("/tmp/ring-mmap-test.bin", 4).unwrap();
+/// let mut consumer = MmapSpscRing::<u64>::open("/tmp/ring-mmap-test.bin").unwrap();
+///
+/// assert_eq!(producer.push_slice(&[1, 2, 3, 4, 5]), 4);
+/// assert_eq!(consumer.try_pop(), Some(1));
+/// assert!(producer.try_push(5).is_ok());
+///
+/// let mut out = [0; 8];
+/// assert_eq!(consumer.pop_slice(&mut out), 4);
+/// assert_eq!(out[..4], [2, 3, 4, 5]);
+/// # }
+/// ```
+pub struct MmapSpscRing<T, B: MappingBackend = DefaultBackend> {
+    map: MmapHeaderSlice<RingHeader, UnsafeCell<T>, B>,
+    // last seen values of the other side's index, saves touching its cache line
+    cached_head: u64,
+    cached_tail: u64,
+}
+
+// slots are only handed between the two sides through the Release/Acquire indices
+unsafe impl<T: Send, B: MappingBackend + Send> Send for MmapSpscRing<T, B> {}
+
+impl<T: MmapSafe, B: MappingBackend> MmapSpscRing<T, B> {
+    /// Creates a ring of `capacity` slots at `path`, replacing any existing file contents.
+    pub fn create(
+        path: impl AsRef<B::Path>,
+        capacity: usize,
+    ) -> Result<MmapSpscRing<T, B>, B::Error> {
+        Self::create_with(
+            path,
+            OpenOptions::new().create(true).truncate(true),
+            capacity,
+        )
+    }
+
+    /// Creates a ring of `capacity` slots at `path` opened according to `options`,
+    /// replacing any existing ring.
+    pub fn create_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+        capacity: usize,
+    ) -> Result<MmapSpscRing<T, B>, B::Error> {
+        assert!(capacity > 0, "ring capacity must not be zero");
+
+        let mut map = MmapHeaderSlice::<RingHeader, UnsafeCell<T>, B>::open_with_len(
+            path, options, capacity,
+        )?;
+        let header = map.header_mut();
+        header.layout.write::<T>(capacity);
+        header.head.value.store(0, Ordering::Relaxed);
+        header.tail.value.store(0, Ordering::Release);
+
+        Ok(Self::from_map(map))
+    }
+
+    /// Opens the ring created at `path`, checking that it holds `T`s.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapSpscRing<T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Opens the ring created at `path` according to `options`, checking that it holds `T`s.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapSpscRing<T, B>, B::Error> {
+        let map = MmapHeaderSlice::<RingHeader, UnsafeCell<T>, B>::open_with(path, options)?;
+        map.header().layout.check::<T>(map.items().len())?;
+
+        let ring = Self::from_map(map);
+        let (head, tail) = (ring.cached_head, ring.cached_tail);
+        if ring.distance(head, tail).is_none() {
+            return Err(HeaderError::Indices { head, tail }.into());
+        }
+
+        Ok(ring)
+    }
+
+    fn from_map(map: MmapHeaderSlice<RingHeader, UnsafeCell<T>, B>) -> MmapSpscRing<T, B> {
+        let header = map.header();
+        let cached_head = header.head.value.load(Ordering::Acquire);
+        let cached_tail = header.tail.value.load(Ordering::Acquire);
+
+        MmapSpscRing {
+            map,
+            cached_head,
+            cached_tail,
+        }
+    }
+
+    fn header(&self) -> &RingHeader {
+        self.map.header()
+    }
+
+    fn slot(&self, index: u64) -> *mut T {
+        let slots = self.map.items();
+        slots[(index % slots.len() as u64) as usize].get()
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.map.items().len()
+    }
+
+    /// Number of queued items, may be out of date as soon as it returns.
+    pub fn len(&self) -> usize {
+        let head = self.header().head.value.load(Ordering::Acquire);
+        let tail = self.header().tail.value.load(Ordering::Acquire);
+        self.distance(head, tail).unwrap_or(0) as usize
+    }
+
+    /// Returns `true` if no items are queued, may be out of date as soon as it returns.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Items from `head` up to `tail`, `None` if they are further apart than the ring has
+    /// slots, which only a corrupted file can do.
+    fn distance(&self, head: u64, tail: u64) -> Option<u64> {
+        let n = tail.wrapping_sub(head);
+        (n <= self.capacity() as u64).then_some(n)
+    }
+
+    /// Free slots for the producer, refreshing the consumer's index only when needed.
+    fn free(&mut self, tail: u64, wanted: usize) -> usize {
+        let cap = self.capacity() as u64;
+        if self
+            .distance(self.cached_head, tail)
+            .is_none_or(|n| cap - n < wanted as u64)
+        {
+            self.cached_head = self.header().head.value.load(Ordering::Acquire);
+        }
+        self.distance(self.cached_head, tail)
+            .map_or(0, |n| (cap - n) as usize)
+    }
+
+    /// Queued items for the consumer, refreshing the producer's index only when needed.
+    fn queued(&mut self, head: u64, wanted: usize) -> usize {
+        if self
+            .distance(head, self.cached_tail)
+            .is_none_or(|n| n < wanted as u64)
+        {
+            self.cached_tail = self.header().tail.value.load(Ordering::Acquire);
+        }
+        self.distance(head, self.cached_tail)
+            .map_or(0, |n| n as usize)
+    }
+
+    /// Appends `value`, handing it back if the ring is full.
+    pub fn try_push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.header().tail.value.load(Ordering::Relaxed);
+        if self.free(tail, 1) == 0 {
+            return Err(value);
+        }
+
+        unsafe { self.slot(tail).write(value) };
+        self.header()
+            .tail
+            .value
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Removes the oldest item, `None` if the ring is empty.
+    pub fn try_pop(&mut self) -> Option<T> {
+        let head = self.header().head.value.load(Ordering::Relaxed);
+        if self.queued(head, 1) == 0 {
+            return None;
+        }
+
+        let value = unsafe { self.slot(head).read() };
+        self.header()
+            .head
+            .value
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+
+    /// Appends as many items of `values` as fit, publishing them at once. Returns how many were pushed.
+    pub fn push_slice(&mut self, values: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        let tail = self.header().tail.value.load(Ordering::Relaxed);
+        let n = self.free(tail, values.len()).min(values.len());
+
+        for (i, value) in values[..n].iter().enumerate() {
+            unsafe { self.slot(tail.wrapping_add(i as u64)).write(*value) };
+        }
+        self.header()
+            .tail
+            .value
+            .store(tail.wrapping_add(n as u64), Ordering::Release);
+
+        n
+    }
+
+    /// Removes up to `out.len()` of the oldest items into `out`. Returns how many were popped.
+    pub fn pop_slice(&mut self, out: &mut [T]) -> usize
+    where
+        T: Copy,
+    {
+        let head = self.header().head.value.load(Ordering::Relaxed);
+        let n = self.queued(head, out.len()).min(out.len());
+
+        for (i, slot) in out[..n].iter_mut().enumerate() {
+            *slot = unsafe { ptr::read(self.slot(head.wrapping_add(i as u64))) };
+        }
+        self.header()
+            .head
+            .value
+            .store(head.wrapping_add(n as u64), Ordering::Release);
+
+        n
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{HeaderError, MmapSpscRing};
+
+    #[test]
+    fn spsc_across_threads() {
+        let path = "/tmp/mmap-wrapper-test-spsc";
+        let _ = std::fs::remove_file(path);
+
+        let mut producer = MmapSpscRing::<u64>::create(path, 64).unwrap();
+        // opened separately like another process would
+        let consumer = std::thread::spawn(move || {
+            let mut ring = MmapSpscRing::<u64>::open(path).unwrap();
+            let (mut sum, mut seen) = (0, 0);
+            let mut buf = [0; 16];
+            while seen < 10_000 {
+                let n = ring.pop_slice(&mut buf);
+                sum += buf[..n].iter().sum::<u64>();
+                seen += n;
+            }
+            sum
+        });
+
+        for i in 0..10_000u64 {
+            let mut value = i;
+            while let Err(v) = producer.try_push(value) {
+                value = v;
+                std::thread::yield_now();
+            }
+        }
+
+        assert_eq!(consumer.join().unwrap(), 10_000 * 9_999 / 2);
+        assert!(producer.is_empty());
+    }
+
+    #[test]
+    fn spsc_rejects_mismatch() {
+        let path = "/tmp/mmap-wrapper-test-spsc-mismatch";
+        let _ = std::fs::remove_file(path);
+
+        MmapSpscRing::<u64>::create(path, 8).unwrap();
+        let e = MmapSpscRing::<u32>::open(path).err().unwrap();
+        assert!(matches!(
+            *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+            HeaderError::Layout { .. }
+        ));
+
+        // a file that was never initialized as a ring
+        std::fs::write(path, [0u8; 4096]).unwrap();
+        let e = MmapSpscRing::<u64>::open(path).err().unwrap();
+        assert!(matches!(
+            *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+            HeaderError::Layout { .. }
+        ));
+    }
+    #[test]
+    fn spsc_corrupt_indices() {
+        use std::os::unix::fs::FileExt;
+
+        let path = "/tmp/mmap-wrapper-test-spsc-corrupt";
+        let _ = std::fs::remove_file(path);
+
+        // the tail index lives on its own cache line at 128
+        let set_tail = |tail: u64| {
+            let f = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+            f.write_all_at(&tail.to_ne_bytes(), 128).unwrap();
+        };
+
+        let mut ring = MmapSpscRing::<u64>::create(path, 4).unwrap();
+        ring.try_push(1).unwrap();
+
+        // a tail past the head by more than the capacity, or behind it
+        for tail in [100, u64::MAX] {
+            set_tail(tail);
+            let e = MmapSpscRing::<u64>::open(path).err().unwrap();
+            assert_eq!(
+                *e.into_inner().unwrap().downcast::<HeaderError>().unwrap(),
+                HeaderError::Indices { head: 0, tail }
+            );
+
+            // an open ring stops moving instead of reading or writing past its slots
+            assert!(ring.try_push(2).is_err());
+            assert_eq!(ring.try_pop(), None);
+            assert_eq!(ring.pop_slice(&mut [0; 4]), 0);
+            assert_eq!(ring.len(), 0);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::{futex, LayoutHasher, MmapRef, MmapSafe};
+
+// the lock word holds the owner's thread id, same split as the kernel's robust futexes
+const TID_MASK: u32 = 0x3fff_ffff;
+const WAITERS: u32 = 0x8000_0000;
+
+// an owner died, the value may be half updated
+const INCONSISTENT: u32 = 1;
+// the value was never repaired, locking always fails
+const NOT_RECOVERABLE: u32 = 2;
+
+/// How often waiters check whether the owner is still alive, a dead owner can't wake them.
+const POLL: Duration = Duration::from_millis(100);
+
+/// A [`MmapMutex`](crate::MmapMutex) that survives its owner dying while holding it.
+///
+/// The lock stores the owning thread id. Waiters check that the owner is still alive and take
+/// over the lock if it isn't, reporting [`RobustLockError::OwnerDied`] so the value can be
+/// repaired and marked consistent with [`MmapRobustMutexGuard::mark_consistent`]. Unlocking
+/// without doing so makes the mutex unusable, every later lock gets
+/// [`RobustLockError::NotRecoverable`].
+///
+/// All processes must share a pid namespace. Thread ids are recycled, an owner that died
+/// while another thread took over its id is only detected once that thread exits too.
+///
+/// Like [`MmapMutex`](crate::MmapMutex) it is only locked through the [`MmapRef`] of a
+/// read-write mapping like [`MmapMutWrapper::read`].
+///
+/// [`MmapMutWrapper::read`]: crate::MmapMutWrapper::read
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use mmap_wrapper::{MmapMutWrapper, MmapRobustMutex, RobustLockError};
+///
+/// # let _ = std::fs::remove_file("/tmp/robust-mmap-test.bin");
+/// let map = MmapMutWrapper::<MmapRobustMutex<[u64; 2]>>::create("/tmp/robust-mmap-test.bin").unwrap();
+/// let mutex = map.read();
+///
+/// let mut pair = match mutex.lock() {
+///     Ok(pair) => pair,
+///     Err(RobustLockError::OwnerDied(mut pair)) => {
+///         // the last owner may have updated only one of them
+///         pair[1] = pair[0];
+///         pair.mark_consistent();
+///         pair
+///     }
+///     Err(RobustLockError::NotRecoverable) => panic!("shared state is lost"),
+/// };
+/// pair[0] += 1;
+/// pair[1] += 1;
+/// # }
+/// ```
+#[repr(C)]
+pub struct MmapRobustMutex<T> {
+    state: AtomicU32,
+    flags: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: MmapSafe> MmapSafe for MmapRobustMutex<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("MmapRobustMutex")
+        .write_u64(T::LAYOUT_HASH)
+        .finish();
+}
+
+unsafe impl<T: Send> Send for MmapRobustMutex<T> {}
+unsafe impl<T: Send> Sync for MmapRobustMutex<T> {}
+
+/// Why a [`MmapRobustMutex`] could not be locked normally.
+pub enum RobustLockError<G> {
+    /// The lock was taken over from an owner that died, repair the value and mark it consistent.
+    OwnerDied(G),
+    /// An earlier owner died and the value was never marked consistent.
+    NotRecoverable,
+}
+
+impl<G> fmt::Debug for RobustLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RobustLockError::OwnerDied(_) => f.write_str("OwnerDied(..)"),
+            RobustLockError::NotRecoverable => f.write_str("NotRecoverable"),
+        }
+    }
+}
+
+impl<G> fmt::Display for RobustLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RobustLockError::OwnerDied(_) => write!(f, "previous owner of the mutex died"),
+            RobustLockError::NotRecoverable => {
+                write!(
+                    f,
+                    "mutex state was never made consistent after its owner died"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<G> std::error::Error for RobustLockError<G> {}
+
+type LockResult<'a, T> =
+    Result<MmapRobustMutexGuard<'a, T>, RobustLockError<MmapRobustMutexGuard<'a, T>>>;
+
+impl<T> MmapRobustMutex<T> {
+    /// An unlocked, consistent mutex holding `value`.
+    pub const fn new(value: T) -> MmapRobustMutex<T> {
+        MmapRobustMutex {
+            state: AtomicU32::new(0),
+            flags: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn take(&self, current: u32, new: u32) -> bool {
+        self.state
+            .compare_exchange(current, new, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn acquired(&self, owner_died: bool) -> LockResult<'_, T> {
+        let guard = MmapRobustMutexGuard { mutex: self };
+
+        if owner_died {
+            self.flags.fetch_or(INCONSISTENT, Ordering::Relaxed);
+        }
+        // also set if the last owner died before repairing it
+        if self.flags.load(Ordering::Relaxed) & INCONSISTENT != 0 {
+            return Err(RobustLockError::OwnerDied(guard));
+        }
+
+        Ok(guard)
+    }
+
+    /// The protected value, no locking needed with exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock(&self) {
+        if self.flags.load(Ordering::Relaxed) & INCONSISTENT != 0 {
+            self.flags.fetch_or(NOT_RECOVERABLE, Ordering::Release);
+        }
+
+        if self.state.swap(0, Ordering::Release) & WAITERS != 0 {
+            futex::wake(&self.state, 1);
+        }
+    }
+}
+
+// locking stores the owner's thread id in the lock word
+impl<T> MmapRef<'_, MmapRobustMutex<T>> {
+    /// Blocks until the mutex is locked by this thread or its owner is found dead.
+    pub fn lock(&self) -> LockResult<'_, T> {
+        let tid = futex::gettid() & TID_MASK;
+        let mut waited = 0;
+
+        loop {
+            if self.flags.load(Ordering::Acquire) & NOT_RECOVERABLE != 0 {
+                return Err(RobustLockError::NotRecoverable);
+            }
+
+            let state = self.state.load(Ordering::Relaxed);
+            let owner = state & TID_MASK;
+            // having slept, assume others still are so unlocking wakes them
+            let locked = tid | (state & WAITERS) | waited;
+
+            if owner == 0 {
+                if self.take(state, locked) {
+                    return self.acquired(false);
+                }
+            } else if !futex::alive(owner) {
+                if self.take(state, locked) {
+                    return self.acquired(true);
+                }
+            } else if state & WAITERS != 0 || self.take(state, state | WAITERS) {
+                futex::wait(&self.state, state | WAITERS, Some(POLL));
+                waited = WAITERS;
+            }
+        }
+    }
+
+    /// Locks the mutex if nobody alive holds it.
+    pub fn try_lock(&self) -> Option<LockResult<'_, T>> {
+        if self.flags.load(Ordering::Acquire) & NOT_RECOVERABLE != 0 {
+            return Some(Err(RobustLockError::NotRecoverable));
+        }
+
+        let tid = futex::gettid() & TID_MASK;
+        let state = self.state.load(Ordering::Relaxed);
+        let owner = state & TID_MASK;
+
+        let dead = owner != 0 && !futex::alive(owner);
+        if (owner == 0 || dead) && self.take(state, tid | (state & WAITERS)) {
+            return Some(self.acquired(dead));
+        }
+
+        None
+    }
+}
+
+/// Holds a [`MmapRobustMutex`] locked, unlocking it when dropped.
+pub struct MmapRobustMutexGuard<'a, T> {
+    mutex: &'a MmapRobustMutex<T>,
+}
+
+impl<T> MmapRobustMutexGuard<'_, T> {
+    /// Marks the value repaired after [`RobustLockError::OwnerDied`], so the mutex keeps working
+    /// once this guard unlocks it.
+    pub fn mark_consistent(&mut self) {
+        self.mutex.flags.fetch_and(!INCONSISTENT, Ordering::Relaxed);
+    }
+}
+
+impl<T> Deref for MmapRobustMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MmapRobustMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MmapRobustMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{MmapMutWrapper, MmapRobustMutex, RobustLockError};
+
+    /// Locks the mutex on a thread that exits without unlocking it.
+    fn die_holding(path: &'static str) {
+        std::thread::spawn(move || {
+            let map = MmapMutWrapper::<MmapRobustMutex<u64>>::open(path).unwrap();
+            let mutex = map.read();
+            let mut value = mutex.lock().unwrap();
+            *value += 1;
+            std::mem::forget(value);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn owner_died_then_repaired() {
+        let path = "/tmp/mmap-wrapper-test-robust-mutex";
+        let _ = std::fs::remove_file(path);
+        let map = MmapMutWrapper::<MmapRobustMutex<u64>>::create(path).unwrap();
+        let mutex = map.read();
+
+        die_holding(path);
+        let mut value = match mutex.lock() {
+            Err(RobustLockError::OwnerDied(value)) => value,
+            r => panic!("expected OwnerDied, got {:?}", r.map(|_| ())),
+        };
+        assert_eq!(*value, 1);
+        value.mark_consistent();
+        drop(value);
+
+        assert_eq!(*mutex.lock().unwrap(), 1);
+
+        // a waiter notices the owner dying while it sleeps
+        let (locked, wait) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn(move || {
+            let map = MmapMutWrapper::<MmapRobustMutex<u64>>::open(path).unwrap();
+            std::mem::forget(map.read().lock().unwrap());
+            locked.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        });
+        wait.recv().unwrap();
+        assert!(matches!(mutex.lock(), Err(RobustLockError::OwnerDied(_))));
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn unrepaired_is_not_recoverable() {
+        let path = "/tmp/mmap-wrapper-test-robust-mutex-lost";
+        let _ = std::fs::remove_file(path);
+        let map = MmapMutWrapper::<MmapRobustMutex<u64>>::create(path).unwrap();
+        let mutex = map.read();
+
+        die_holding(path);
+        assert!(matches!(
+            mutex.try_lock(),
+            Some(Err(RobustLockError::OwnerDied(_)))
+        ));
+        assert!(matches!(mutex.lock(), Err(RobustLockError::NotRecoverable)));
+    }
+}
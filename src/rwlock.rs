@@ -0,0 +1,396 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::{futex, LayoutHasher, MmapRef, MmapSafe};
+
+const READERS: u32 = (1 << 29) - 1;
+const WRITE_LOCKED: u32 = 1 << 29;
+const READERS_WAITING: u32 = 1 << 30;
+const WRITERS_WAITING: u32 = 1 << 31;
+const WAITING: u32 = READERS_WAITING | WRITERS_WAITING;
+
+// longest sleep of `load` between checks for the writer to be done
+const LOAD_MAX_SLEEP: Duration = Duration::from_millis(1);
+
+/// A reader-writer lock that can be stored in a mapped file and shared by every process mapping it.
+///
+/// Locking for reading or writing blocks on Linux futexes and changes the lock word, so it is
+/// only offered on the [`MmapRef`] of a read-write mapping like [`MmapMutWrapper::read`].
+/// [`MmapRwLock::load`] copies the value out with nothing but atomic loads, retrying if a writer
+/// was active, so readers can use a read-only [`MmapWrapper`].
+///
+/// By default readers get in whenever no writer holds the lock, which can starve writers under
+/// constant reading. [`MmapRwLock::writer_preferring`] or `set_writer_preferring` on an existing
+/// lock makes new readers wait for waiting writers. A zeroed `MmapRwLock` is unlocked and prefers
+/// readers.
+///
+/// [`MmapMutWrapper::read`]: crate::MmapMutWrapper::read
+/// [`MmapWrapper`]: crate::MmapWrapper
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapMutWrapper, MmapRwLock, MmapSafe, MmapWrapper};
+///
+/// #[derive(MmapSafe, Clone, Copy)]
+/// #[repr(C)]
+/// struct Config {
+///    workers: u32,
+///    timeout_ms: u32,
+/// }
+///
+/// # let _ = std::fs::remove_file("/tmp/rwlock-mmap-test.bin");
+/// let writer = MmapMutWrapper::<MmapRwLock<Config>>::create("/tmp/rwlock-mmap-test.bin").unwrap();
+/// writer.read().write().workers = 8;
+///
+/// // a process that may only read the file
+/// let reader = MmapWrapper::<MmapRwLock<Config>>::open("/tmp/rwlock-mmap-test.bin").unwrap();
+/// assert_eq!(reader.read().load().workers, 8);
+/// # }
+/// ```
+///
+/// Read-only mappings can't take the lock:
+/// ```rust,compile_fail
+/// use mmap_wrapper::{MmapRwLock, MmapWrapper};
+///
+/// let reader = MmapWrapper::<MmapRwLock<u64>>::open("/tmp/rwlock-mmap-test.bin").unwrap();
+/// let value = *reader.read().read();
+/// ```
+#[repr(C)]
+pub struct MmapRwLock<T> {
+    state: AtomicU32,
+    // odd while a writer holds the lock, read by `load`
+    seq: AtomicU32,
+    prefer_writers: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: MmapSafe> MmapSafe for MmapRwLock<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("MmapRwLock")
+        .write_u64(T::LAYOUT_HASH)
+        .finish();
+}
+
+unsafe impl<T: Send> Send for MmapRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for MmapRwLock<T> {}
+
+impl<T> MmapRwLock<T> {
+    /// An unlocked lock holding `value` that lets readers in while writers wait.
+    pub const fn new(value: T) -> MmapRwLock<T> {
+        MmapRwLock {
+            state: AtomicU32::new(0),
+            seq: AtomicU32::new(0),
+            prefer_writers: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// An unlocked lock holding `value` that makes new readers wait while a writer is waiting.
+    pub const fn writer_preferring(value: T) -> MmapRwLock<T> {
+        MmapRwLock {
+            state: AtomicU32::new(0),
+            seq: AtomicU32::new(0),
+            prefer_writers: AtomicU32::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn can_read(&self, state: u32) -> bool {
+        state & WRITE_LOCKED == 0
+            && state & READERS < READERS
+            && (self.prefer_writers.load(Ordering::Relaxed) == 0 || state & WRITERS_WAITING == 0)
+    }
+
+    /// Sleeps until the lock word changes from `state`, flagging that someone is waiting.
+    fn wait(&self, state: u32, flag: u32) {
+        if state & flag == 0
+            && self
+                .state
+                .compare_exchange(state, state | flag, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        futex::wait(&self.state, state | flag, None);
+    }
+
+    /// Copies the value out without taking the lock, so it works on read-only mappings.
+    ///
+    /// Retries until no writer was active during the copy, sleeping while one holds the lock.
+    /// A read-only mapping can't flag that it is waiting, so sleeps end on their own after at
+    /// most a millisecond instead of being woken by the writer.
+    pub fn load(&self) -> T
+    where
+        T: Copy,
+    {
+        let mut spins = 0;
+        let mut sleep = Duration::from_micros(10);
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                // spin briefly in case the writer is about to let go
+                if spins < 100 {
+                    spins += 1;
+                    core::hint::spin_loop();
+                } else {
+                    futex::wait(&self.seq, seq, Some(sleep));
+                    sleep = (sleep * 2).min(LOAD_MAX_SLEEP);
+                }
+                continue;
+            }
+
+            let value = unsafe { ptr::read_volatile(self.value.get()) };
+
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return value;
+            }
+        }
+    }
+
+    /// The protected value, no locking needed with exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn read_unlock(&self) {
+        let state = self.state.fetch_sub(1, Ordering::Release) - 1;
+        if state & READERS == 0 && state & WAITING != 0 {
+            self.wake(state);
+        }
+    }
+
+    fn write_unlock(&self) {
+        // readers sleeping in `load` wake up on their own
+        self.seq.fetch_add(1, Ordering::Release);
+
+        let state = self.state.fetch_and(!WRITE_LOCKED, Ordering::Release) & !WRITE_LOCKED;
+        if state & WAITING != 0 {
+            self.wake(state);
+        }
+    }
+
+    /// Clears the waiting flags and wakes everyone, whoever loses the race flags again.
+    ///
+    /// Leaves it to the next unlock if someone took the lock in the meantime.
+    fn wake(&self, mut state: u32) {
+        while state & WAITING != 0 && state & (READERS | WRITE_LOCKED) == 0 {
+            match self.state.compare_exchange(
+                state,
+                state & !WAITING,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return futex::wake(&self.state, u32::MAX),
+                Err(actual) => state = actual,
+            }
+        }
+    }
+}
+
+// read and write guards both take the lock word, `load` only watches it
+impl<T> MmapRef<'_, MmapRwLock<T>> {
+    /// Blocks until the value can be read alongside other readers.
+    ///
+    /// Use [`MmapRwLock::load`] on read-only mappings.
+    pub fn read(&self) -> MmapRwLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+
+            let state = self.state.load(Ordering::Relaxed);
+            if !self.can_read(state) {
+                self.wait(state, READERS_WAITING);
+            }
+        }
+    }
+
+    /// Locks for reading if no writer holds the lock (or waits for it when writer-preferring).
+    pub fn try_read(&self) -> Option<MmapRwLockReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while self.can_read(state) {
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(MmapRwLockReadGuard { lock: self }),
+                Err(actual) => state = actual,
+            }
+        }
+
+        None
+    }
+
+    /// Blocks until the value can be written with no readers or other writers.
+    pub fn write(&self) -> MmapRwLockWriteGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+
+            let state = self.state.load(Ordering::Relaxed);
+            if state & (READERS | WRITE_LOCKED) != 0 {
+                self.wait(state, WRITERS_WAITING);
+            }
+        }
+    }
+
+    /// Makes new readers wait while a writer is waiting, or lets them in again.
+    ///
+    /// Applies to every process sharing the lock, readers already waiting pick it up
+    /// the next time they are woken.
+    pub fn set_writer_preferring(&self, prefer: bool) {
+        self.prefer_writers.store(prefer as u32, Ordering::Relaxed);
+    }
+
+    /// Locks for writing if nobody holds the lock.
+    pub fn try_write(&self) -> Option<MmapRwLockWriteGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & (READERS | WRITE_LOCKED) == 0 {
+            match self.state.compare_exchange_weak(
+                state,
+                state | WRITE_LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.seq.fetch_add(1, Ordering::Relaxed);
+                    fence(Ordering::Release);
+                    return Some(MmapRwLockWriteGuard { lock: self });
+                }
+                Err(actual) => state = actual,
+            }
+        }
+
+        None
+    }
+}
+
+/// Holds a [`MmapRwLock`] locked for reading, unlocking it when dropped.
+pub struct MmapRwLockReadGuard<'a, T> {
+    lock: &'a MmapRwLock<T>,
+}
+
+impl<T> Deref for MmapRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MmapRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+/// Holds a [`MmapRwLock`] locked for writing, unlocking it when dropped.
+pub struct MmapRwLockWriteGuard<'a, T> {
+    lock: &'a MmapRwLock<T>,
+}
+
+impl<T> Deref for MmapRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for MmapRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MmapRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{MmapMutWrapper, MmapRwLock, MmapWrapper};
+
+    #[test]
+    fn readers_and_writers_across_mappings() {
+        let path = "/tmp/mmap-wrapper-test-rwlock";
+        let _ = std::fs::remove_file(path);
+        MmapMutWrapper::<MmapRwLock<[u64; 4]>>::create(path).unwrap();
+
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let map = MmapMutWrapper::<MmapRwLock<[u64; 4]>>::open(path).unwrap();
+                    let lock = map.read();
+                    for _ in 0..5_000 {
+                        lock.write().iter_mut().for_each(|x| *x += 1);
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..2)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let check = |v: &[u64; 4]| assert!(v.iter().all(|&x| x == v[0]));
+                    for _ in 0..5_000 {
+                        if i == 0 {
+                            let map = MmapWrapper::<MmapRwLock<[u64; 4]>>::open(path).unwrap();
+                            check(&map.read().load());
+                        } else {
+                            let map = MmapMutWrapper::<MmapRwLock<[u64; 4]>>::open(path).unwrap();
+                            check(&map.read().read());
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        writers.into_iter().for_each(|t| t.join().unwrap());
+        readers.into_iter().for_each(|t| t.join().unwrap());
+
+        let map = MmapMutWrapper::<MmapRwLock<[u64; 4]>>::open(path).unwrap();
+        let lock = map.read();
+        assert_eq!(*lock.read(), [10_000; 4]);
+
+        let r1 = lock.read();
+        let r2 = lock.try_read().unwrap();
+        assert!(lock.try_write().is_none());
+        drop((r1, r2));
+        let w = lock.try_write().unwrap();
+        assert!(lock.try_read().is_none());
+        drop(w);
+    }
+
+    #[test]
+    fn writer_preferring_blocks_new_readers() {
+        let path = "/tmp/mmap-wrapper-test-rwlock-writer-preferring";
+        let _ = std::fs::remove_file(path);
+        let map = MmapMutWrapper::<MmapRwLock<u64>>::create(path).unwrap();
+        let lock = map.read();
+        lock.set_writer_preferring(true);
+
+        let reading = lock.read();
+        let writer = std::thread::spawn(move || {
+            let map = MmapMutWrapper::<MmapRwLock<u64>>::open(path).unwrap();
+            *map.read().write() += 1;
+        });
+        while lock.try_read().is_some() {
+            std::thread::yield_now();
+        }
+        // a writer is waiting, so the next reader has to as well
+        drop(reading);
+        writer.join().unwrap();
+        assert_eq!(lock.load(), 1);
+    }
+}
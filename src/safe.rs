@@ -0,0 +1,165 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::sync::atomic;
+
+/// Marker for types that can be read straight out of a memory-mapped file.
+///
+/// The wrappers only hand out references to types implementing this trait,
+/// so the bytes backing the mapping are always a valid `T`.
+///
+/// Prefer `#[derive(MmapSafe)]` (`derive` feature) over implementing it by hand:
+/// ```rust
+/// # #[cfg(feature = "derive")] {
+/// use mmap_wrapper::MmapSafe;
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct MyStruct {
+///    thing1: i64,
+///    thing2: f64,
+/// }
+/// # }
+/// ```
+///
+/// The derive rejects types that can't be mapped:
+/// ```rust,compile_fail
+/// use mmap_wrapper::MmapSafe;
+///
+/// // missing #[repr(C)]
+/// #[derive(MmapSafe)]
+/// struct MyStruct {
+///    thing1: i64,
+/// }
+/// ```
+/// ```rust,compile_fail
+/// use mmap_wrapper::MmapSafe;
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct MyStruct {
+///    thing1: &'static i32,
+/// }
+/// ```
+/// ```rust,compile_fail
+/// use mmap_wrapper::MmapSafe;
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct MyStruct {
+///    // not every bit pattern is a valid bool
+///    thing1: bool,
+/// }
+/// ```
+/// ```rust,compile_fail
+/// use mmap_wrapper::MmapSafe;
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct MyStruct {
+///    thing1: i32,
+///    // 4 padding bytes, add `_pad: u32` to fill them
+///    thing2: f64,
+/// }
+/// ```
+///
+/// # Safety
+///
+/// - `T` must have a consistent memory layout, use `#[repr(transparent)]` if `T` is a newtype
+///   wrapper around a single field otherwise `#[repr(C)]`.
+/// - Every bit pattern must be a valid `T`, which rules out `bool`, `char`, enums and `NonZero*`.
+/// - `T` must not contain references, pointers or owning types like `Box`, they are meaningless
+///   once written to a file.
+/// - `T` must not have padding bytes. Writing a whole `T` leaves them uninitialized and views
+///   of the mapping, like [`MmapWrapper::view`], can read them back as plain bytes.
+///
+/// [`MmapWrapper::view`]: crate::MmapWrapper::view
+pub unsafe trait MmapSafe: Sized {
+    /// Fingerprint of the memory layout of `Self`, stored in file headers to catch
+    /// files written by a different version of a struct.
+    ///
+    /// The derive hashes the offset and `LAYOUT_HASH` of every field, the default
+    /// only covers size and alignment.
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_u64(size_of::<Self>() as u64)
+        .write_u64(align_of::<Self>() as u64)
+        .finish();
+}
+
+/// Const FNV-1a hasher behind [`MmapSafe::LAYOUT_HASH`], used by the derive macro.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy)]
+pub struct LayoutHasher(u64);
+
+impl Default for LayoutHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LayoutHasher {
+    pub const fn new() -> LayoutHasher {
+        LayoutHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub const fn write(mut self, bytes: &[u8]) -> LayoutHasher {
+        let mut i = 0;
+        while i < bytes.len() {
+            self.0 ^= bytes[i] as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+            i += 1;
+        }
+        self
+    }
+
+    pub const fn write_str(self, s: &str) -> LayoutHasher {
+        self.write(s.as_bytes())
+    }
+
+    pub const fn write_u64(self, v: u64) -> LayoutHasher {
+        self.write(&v.to_le_bytes())
+    }
+
+    pub const fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+macro_rules! impl_mmap_safe {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl MmapSafe for $t {
+            const LAYOUT_HASH: u64 = LayoutHasher::new().write_str(stringify!($t)).finish();
+        })*
+    };
+}
+
+impl_mmap_safe!(u8, u16, u32, u64, u128, usize);
+impl_mmap_safe!(i8, i16, i32, i64, i128, isize);
+impl_mmap_safe!(f32, f64, ());
+
+#[cfg(target_has_atomic = "8")]
+impl_mmap_safe!(atomic::AtomicU8, atomic::AtomicI8);
+#[cfg(target_has_atomic = "16")]
+impl_mmap_safe!(atomic::AtomicU16, atomic::AtomicI16);
+#[cfg(target_has_atomic = "32")]
+impl_mmap_safe!(atomic::AtomicU32, atomic::AtomicI32);
+#[cfg(target_has_atomic = "64")]
+impl_mmap_safe!(atomic::AtomicU64, atomic::AtomicI64);
+#[cfg(target_has_atomic = "ptr")]
+impl_mmap_safe!(atomic::AtomicUsize, atomic::AtomicIsize);
+
+unsafe impl<T: MmapSafe, const N: usize> MmapSafe for [T; N] {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_u64(T::LAYOUT_HASH)
+        .write_u64(N as u64)
+        .finish();
+}
+
+// UnsafeCell<T> has the same layout as T
+unsafe impl<T: MmapSafe> MmapSafe for UnsafeCell<T> {
+    const LAYOUT_HASH: u64 = T::LAYOUT_HASH;
+}
+
+unsafe impl<T: ?Sized> MmapSafe for PhantomData<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new().write_str("PhantomData").finish();
+}
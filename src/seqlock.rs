@@ -0,0 +1,278 @@
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use core::time::Duration;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::futex;
+use crate::{LayoutHasher, MmapRef, MmapSafe};
+
+// longest sleep between checks for a writer to be done
+const MAX_SLEEP: Duration = Duration::from_millis(1);
+
+/// A sequence lock for snapshots of a `T` written by one process and polled by many.
+///
+/// Writers make the sequence number odd while they change the value, readers copy the value
+/// and retry if the sequence number was odd or changed in the meantime. Reading only ever
+/// loads from the mapping, so [`MmapSeqLock::load`] works on a read-only [`MmapWrapper`].
+///
+/// Writes with `store` and `update` are only offered on the [`MmapRef`] of a read-write mapping
+/// like [`MmapMutWrapper::read`] and exclude each other. Readers and other writers wait for as
+/// long as a write takes, spinning briefly before sleeping up to a millisecond between checks,
+/// so keep writes short. A zeroed `MmapSeqLock` holds a zeroed `T`.
+///
+/// A writer process that dies in the middle of a write leaves the lock held for good: `load`,
+/// `store` and `update` never return and [`MmapSeqLock::try_load`] keeps returning `None`.
+///
+/// [`MmapWrapper`]: crate::MmapWrapper
+/// [`MmapMutWrapper::read`]: crate::MmapMutWrapper::read
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapMutWrapper, MmapSafe, MmapSeqLock, MmapWrapper};
+///
+/// #[derive(MmapSafe, Clone, Copy)]
+/// #[repr(C)]
+/// struct Telemetry {
+///    requests: u64,
+///    errors: u64,
+/// }
+///
+/// let writer = MmapMutWrapper::<MmapSeqLock<Telemetry>>::create("/tmp/seqlock-mmap-test.bin").unwrap();
+/// writer.read().store(Telemetry { requests: 10, errors: 0 });
+/// writer.read().update(|t| {
+///     t.requests += 1;
+///     t.errors += 1;
+/// });
+///
+/// // pollers never need write access
+/// let reader = MmapWrapper::<MmapSeqLock<Telemetry>>::open("/tmp/seqlock-mmap-test.bin").unwrap();
+/// let snapshot = reader.read().load();
+/// assert_eq!((snapshot.requests, snapshot.errors), (11, 1));
+/// # }
+/// ```
+///
+/// Read-only mappings can't write:
+/// ```rust,compile_fail
+/// use mmap_wrapper::{MmapSeqLock, MmapWrapper};
+///
+/// let reader = MmapWrapper::<MmapSeqLock<u64>>::open("/tmp/seqlock-mmap-test.bin").unwrap();
+/// reader.read().store(1);
+/// ```
+#[repr(C)]
+pub struct MmapSeqLock<T> {
+    seq: AtomicU64,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: MmapSafe> MmapSafe for MmapSeqLock<T> {
+    const LAYOUT_HASH: u64 = LayoutHasher::new()
+        .write_str("MmapSeqLock")
+        .write_u64(T::LAYOUT_HASH)
+        .finish();
+}
+
+unsafe impl<T: Copy + Send> Send for MmapSeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for MmapSeqLock<T> {}
+
+impl<T: Copy> MmapSeqLock<T> {
+    /// A seqlock holding `value`.
+    pub const fn new(value: T) -> MmapSeqLock<T> {
+        MmapSeqLock {
+            seq: AtomicU64::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Copies the value out, retrying until no write overlapped the copy.
+    pub fn load(&self) -> T {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(value) = self.try_load() {
+                return value;
+            }
+            backoff.wait();
+        }
+    }
+
+    /// Copies the value out, `None` if a write overlapped the copy.
+    pub fn try_load(&self) -> Option<T> {
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq % 2 == 1 {
+            return None;
+        }
+
+        let value = unsafe { ptr::read_volatile(self.value.get()) };
+
+        fence(Ordering::Acquire);
+        (self.seq.load(Ordering::Relaxed) == seq).then_some(value)
+    }
+
+    /// The value, no retrying needed with exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+// writers bump the sequence number, readers only load it
+impl<T: Copy> MmapRef<'_, MmapSeqLock<T>> {
+    /// Replaces the value.
+    pub fn store(&self, value: T) {
+        self.update(|v| *v = value);
+    }
+
+    /// Changes the value in place, readers see all of `f`'s changes or none of them.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        let mut backoff = Backoff::new();
+        loop {
+            if seq % 2 == 1 {
+                backoff.wait();
+                seq = self.seq.load(Ordering::Relaxed);
+                continue;
+            }
+            match self
+                .seq
+                .compare_exchange_weak(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(actual) => seq = actual,
+            }
+        }
+        fence(Ordering::Release);
+        // unlocks even if `f` panics, the mapping still holds the old value then
+        let _unlock = Unlock {
+            seq: &self.seq,
+            next: seq + 2,
+        };
+
+        // work on a copy so readers never see `f` halfway through
+        let mut value = unsafe { ptr::read_volatile(self.value.get()) };
+        f(&mut value);
+        unsafe { ptr::write_volatile(self.value.get(), value) };
+    }
+}
+
+/// Waits for a writer to be done, spinning at first and then sleeping longer and longer.
+struct Backoff {
+    spins: u32,
+    sleep: Duration,
+}
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff {
+            spins: 0,
+            sleep: Duration::from_micros(10),
+        }
+    }
+
+    fn wait(&mut self) {
+        // the writer is likely about to let go
+        if self.spins < 100 {
+            self.spins += 1;
+            hint::spin_loop();
+            return;
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        futex::sleep(self.sleep);
+        #[cfg(all(feature = "std", not(any(target_os = "linux", target_os = "android"))))]
+        std::thread::sleep(self.sleep);
+        // no way to sleep left, keep spinning
+        #[cfg(not(any(feature = "std", target_os = "linux", target_os = "android")))]
+        hint::spin_loop();
+
+        self.sleep = (self.sleep * 2).min(MAX_SLEEP);
+    }
+}
+
+/// Makes the sequence number even again when a write is done.
+struct Unlock<'a> {
+    seq: &'a AtomicU64,
+    next: u64,
+}
+
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.seq.store(self.next, Ordering::Release);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{MmapMutWrapper, MmapSeqLock, MmapWrapper};
+
+    #[test]
+    fn readers_never_see_torn_writes() {
+        let path = "/tmp/mmap-wrapper-test-seqlock";
+        let _ = std::fs::remove_file(path);
+        let writer = MmapMutWrapper::<MmapSeqLock<[u64; 16]>>::create(path).unwrap();
+
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let map = MmapWrapper::<MmapSeqLock<[u64; 16]>>::open(path).unwrap();
+                    let mut last = 0;
+                    while last < 20_000 {
+                        let v = map.read().load();
+                        assert!(v.iter().all(|&x| x == v[0]));
+                        assert!(v[0] >= last);
+                        last = v[0];
+                    }
+                })
+            })
+            .collect();
+
+        let lock = writer.read();
+        for i in 1..=20_000 {
+            if i % 2 == 0 {
+                lock.store([i; 16]);
+            } else {
+                lock.update(|v| v.iter_mut().for_each(|x| *x += 1));
+            }
+        }
+        readers.into_iter().for_each(|t| t.join().unwrap());
+    }
+
+    #[test]
+    fn panicking_update_unlocks() {
+        let path = "/tmp/mmap-wrapper-test-seqlock-panic";
+        let _ = std::fs::remove_file(path);
+        let map = MmapMutWrapper::<MmapSeqLock<u64>>::create(path).unwrap();
+        let lock = map.read();
+        lock.store(3);
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lock.update(|_| panic!("bad update"))
+        }));
+        assert!(res.is_err());
+
+        // readers and writers carry on with the old value
+        assert_eq!(lock.try_load(), Some(3));
+        lock.update(|v| *v += 1);
+        assert_eq!(lock.load(), 4);
+    }
+
+    #[test]
+    fn load_waits_out_a_slow_writer() {
+        let path = "/tmp/mmap-wrapper-test-seqlock-slow";
+        let _ = std::fs::remove_file(path);
+        let map = MmapMutWrapper::<MmapSeqLock<u64>>::create(path).unwrap();
+        map.read().store(5);
+
+        // a writer in another process, stuck for a while halfway through
+        let mut seq = MmapMutWrapper::<u64>::open(path).unwrap();
+        *seq.write() += 1;
+        assert_eq!(map.read().try_load(), None);
+
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            *seq.write() += 1;
+        });
+        assert_eq!(map.read().load(), 5);
+        writer.join().unwrap();
+    }
+}
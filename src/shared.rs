@@ -0,0 +1,85 @@
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::borrow::BorrowFlag;
+use crate::{LayoutError, MappingBackend};
+
+/// A reference counted mapping, like `Arc<B>` with a [`BorrowFlag`] attached.
+///
+/// The count lives in a small anonymous mapping made by the backend itself,
+/// so std and no_std builds share this code without needing an allocator.
+pub(crate) struct Shared<B: MappingBackend> {
+    ctrl: NonNull<Control<B>>,
+}
+
+struct Control<B> {
+    refs: AtomicUsize,
+    borrow: BorrowFlag,
+    map: B,
+    // the anonymous mapping this struct is stored in
+    page: B,
+}
+
+// Shared only hands out `&B` and the flag, same bounds as `Arc<B>`.
+unsafe impl<B: MappingBackend + Send + Sync> Send for Shared<B> {}
+unsafe impl<B: MappingBackend + Send + Sync> Sync for Shared<B> {}
+
+impl<B: MappingBackend> Shared<B> {
+    pub(crate) fn new(map: B) -> Result<Shared<B>, B::Error> {
+        let page = B::map_anon(size_of::<Control<B>>())?;
+        LayoutError::check::<Control<B>>(page.as_ptr(), page.len())?;
+
+        let ctrl = page.as_ptr().cast::<Control<B>>();
+        unsafe {
+            ctrl.write(Control {
+                refs: AtomicUsize::new(1),
+                borrow: BorrowFlag::new(),
+                map,
+                page,
+            });
+        }
+
+        Ok(Shared {
+            ctrl: unsafe { NonNull::new_unchecked(ctrl) },
+        })
+    }
+
+    fn control(&self) -> &Control<B> {
+        unsafe { self.ctrl.as_ref() }
+    }
+
+    pub(crate) fn backend(&self) -> &B {
+        &self.control().map
+    }
+
+    pub(crate) fn borrow(&self) -> &BorrowFlag {
+        &self.control().borrow
+    }
+}
+
+impl<B: MappingBackend> Clone for Shared<B> {
+    fn clone(&self) -> Self {
+        // same reasoning as Arc::clone, new references can only be
+        // made from an existing one so no synchronization is needed
+        self.control().refs.fetch_add(1, Ordering::Relaxed);
+
+        Shared { ctrl: self.ctrl }
+    }
+}
+
+impl<B: MappingBackend> Drop for Shared<B> {
+    fn drop(&mut self) {
+        if self.control().refs.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // pairs with the Release above so every other clone is done with the mapping
+        fence(Ordering::Acquire);
+
+        // move everything out before `page` unmaps the memory it lives in
+        let Control { map, page, .. } = unsafe { self.ctrl.as_ptr().read() };
+        drop(map);
+        drop(page);
+    }
+}
@@ -0,0 +1,417 @@
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+
+use crate::borrow::{BorrowError, MmapRef, MmapRefMut};
+use crate::header::{self, FileHeader};
+use crate::shared::Shared;
+use crate::{DefaultBackend, LayoutError, MappingBackend, MmapSafe, OpenOptions};
+
+/// A wrapper for a memory-mapped file holding an array of `T` records.
+///
+/// The number of records comes from the file size, which must be a multiple of `size_of::<T>()`.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapSafe, MmapSliceMutWrapper, MmapSliceWrapper};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct Record {
+///    id: u32,
+///    value: f32,
+/// }
+///
+/// let mut records = MmapSliceMutWrapper::<Record>::create("/tmp/records-mmap-test.bin", 16).unwrap();
+/// records.write()[3].id = 3;
+///
+/// let records = MmapSliceWrapper::<Record>::open("/tmp/records-mmap-test.bin").unwrap();
+/// assert_eq!(records.len(), 16);
+/// assert_eq!(records.get(3).map(|r| r.id), Some(3));
+/// # }
+/// ```
+pub struct MmapSliceWrapper<T, B: MappingBackend = DefaultBackend> {
+    raw: Shared<B>,
+    offset: usize,
+    len: usize,
+    _inner: PhantomData<T>,
+}
+
+/// A mutable wrapper for a memory-mapped file holding an array of `T` records.
+///
+/// Clones share the mapping and a borrow flag like [`MmapMutWrapper`](crate::MmapMutWrapper).
+pub struct MmapSliceMutWrapper<T, B: MappingBackend = DefaultBackend> {
+    raw: Shared<B>,
+    offset: usize,
+    len: usize,
+    _inner: PhantomData<T>,
+}
+
+// same bounds as the single value wrappers, clones share `&[T]` between threads
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Send for MmapSliceWrapper<T, B> {}
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Sync for MmapSliceWrapper<T, B> {}
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Send for MmapSliceMutWrapper<T, B> {}
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Sync for MmapSliceMutWrapper<T, B> {}
+
+impl<T, B: MappingBackend> Clone for MmapSliceWrapper<T, B> {
+    fn clone(&self) -> Self {
+        MmapSliceWrapper {
+            raw: self.raw.clone(),
+            offset: self.offset,
+            len: self.len,
+            _inner: PhantomData,
+        }
+    }
+}
+
+impl<T, B: MappingBackend> Clone for MmapSliceMutWrapper<T, B> {
+    fn clone(&self) -> Self {
+        MmapSliceMutWrapper {
+            raw: self.raw.clone(),
+            offset: self.offset,
+            len: self.len,
+            _inner: PhantomData,
+        }
+    }
+}
+
+/// Checks the header and records of `backend`, returning where they start and how many there are.
+fn layout<T: MmapSafe, B: MappingBackend>(
+    backend: &B,
+    version: Option<u32>,
+    fresh: bool,
+) -> Result<(usize, usize), B::Error> {
+    let offset = header::init::<T, B>(backend, version, fresh)?;
+    // the header may be padded past the end of a short file to align the records
+    let bytes = backend
+        .len()
+        .checked_sub(offset)
+        .ok_or(LayoutError::TooSmall {
+            len: backend.len(),
+            required: offset,
+        })?;
+    let len = LayoutError::check_slice::<T>(backend.as_ptr().wrapping_add(offset), bytes)?;
+
+    Ok((offset, len))
+}
+
+impl<T: MmapSafe, B: MappingBackend> MmapSliceWrapper<T, B> {
+    /// Wraps an existing mapping, checking that it holds a whole number of records aligned for `T`.
+    pub fn from_backend(backend: B) -> Result<MmapSliceWrapper<T, B>, B::Error> {
+        let (offset, len) = layout::<T, B>(&backend, None, false)?;
+        Self::from_parts(backend, offset, len)
+    }
+
+    fn from_parts(
+        backend: B,
+        offset: usize,
+        len: usize,
+    ) -> Result<MmapSliceWrapper<T, B>, B::Error> {
+        Ok(MmapSliceWrapper {
+            raw: Shared::new(backend)?,
+            offset,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    /// Maps the whole existing file at `path` read-only.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapSliceWrapper<T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the whole file at `path` read-only, opening it according to `options`.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapSliceWrapper<T, B>, B::Error> {
+        header::probe::<T, B>(path.as_ref(), options, false)?;
+        let backend = B::map(path.as_ref(), options, None, false)?;
+        let (offset, len) = layout::<T, B>(&backend, options.header, false)?;
+        Self::from_parts(backend, offset, len)
+    }
+
+    /// The mapped records.
+    pub fn as_slice(&self) -> &[T] {
+        let data = unsafe { self.raw.backend().as_ptr().add(self.offset) };
+        unsafe { slice::from_raw_parts(data.cast::<T>(), self.len) }
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the file holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The record at `index`, `None` if out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.as_slice().get(index)
+    }
+
+    /// Iterates over the records.
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+}
+
+impl<'a, T: MmapSafe, B: MappingBackend> IntoIterator for &'a MmapSliceWrapper<T, B> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> slice::Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T: MmapSafe, B: MappingBackend> MmapSliceMutWrapper<T, B> {
+    /// Wraps an existing writable mapping, checking that it holds a whole number of records
+    /// aligned for `T`.
+    ///
+    /// Fails with [`LayoutError::ReadOnly`] for a read-only mapping.
+    pub fn from_backend(backend: B) -> Result<MmapSliceMutWrapper<T, B>, B::Error> {
+        if !backend.writable() {
+            return Err(LayoutError::ReadOnly.into());
+        }
+        let (offset, len) = layout::<T, B>(&backend, None, false)?;
+        Self::from_parts(backend, offset, len)
+    }
+
+    fn from_parts(
+        backend: B,
+        offset: usize,
+        len: usize,
+    ) -> Result<MmapSliceMutWrapper<T, B>, B::Error> {
+        Ok(MmapSliceMutWrapper {
+            raw: Shared::new(backend)?,
+            offset,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    /// Maps the file at `path` read-write, creating it if necessary
+    /// and resizing it to exactly `len` records.
+    pub fn create(
+        path: impl AsRef<B::Path>,
+        len: usize,
+    ) -> Result<MmapSliceMutWrapper<T, B>, B::Error> {
+        Self::open_with_len(path, OpenOptions::new().create(true).truncate(true), len)
+    }
+
+    /// Maps the whole existing file at `path` read-write without resizing it.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapSliceMutWrapper<T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the whole file at `path` read-write, opening it according to `options`.
+    ///
+    /// There is no length to resize the file to, see [`MmapSliceMutWrapper::open_with_len`].
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapSliceMutWrapper<T, B>, B::Error> {
+        let fresh = header::probe::<T, B>(path.as_ref(), options, true)?;
+        let backend = B::map(path.as_ref(), options, None, true)?;
+        let (offset, len) = layout::<T, B>(&backend, options.header, fresh)?;
+        Self::from_parts(backend, offset, len)
+    }
+
+    /// Maps the first `len` records of the file at `path` read-write,
+    /// creating or resizing it according to `options`.
+    pub fn open_with_len(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+        len: usize,
+    ) -> Result<MmapSliceMutWrapper<T, B>, B::Error> {
+        let offset = match options.header {
+            Some(_) => FileHeader::data_offset::<T>(),
+            None => 0,
+        };
+        let map_len = len
+            .checked_mul(size_of::<T>())
+            .and_then(|n| n.checked_add(offset))
+            .ok_or(LayoutError::Overflow)?;
+
+        let fresh = header::probe::<T, B>(path.as_ref(), options, true)?;
+        let backend = B::map(path.as_ref(), options, Some(map_len), true)?;
+        let (offset, len) = layout::<T, B>(&backend, options.header, fresh)?;
+        Self::from_parts(backend, offset, len)
+    }
+
+    fn ptr(&self) -> *mut [T] {
+        let data = unsafe { self.raw.backend().as_ptr().add(self.offset) };
+        ptr::slice_from_raw_parts_mut(data.cast::<T>(), self.len)
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the file holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Shared access to the mapped records.
+    ///
+    /// # Panics
+    /// Panics if a clone of this wrapper holds a write guard,
+    /// use [`MmapSliceMutWrapper::try_read`] to handle this case.
+    pub fn read(&self) -> MmapRef<'_, [T]> {
+        self.try_read()
+            .expect("mapping is already mutably borrowed")
+    }
+
+    /// Shared access to the mapped records, fails if a clone of this wrapper holds a write guard.
+    pub fn try_read(&self) -> Result<MmapRef<'_, [T]>, BorrowError> {
+        unsafe { MmapRef::new(self.ptr(), self.raw.borrow()) }
+    }
+
+    /// Exclusive access to the mapped records.
+    ///
+    /// # Panics
+    /// Panics if a clone of this wrapper holds any guard,
+    /// use [`MmapSliceMutWrapper::try_write`] to handle this case.
+    pub fn write(&mut self) -> MmapRefMut<'_, [T]> {
+        self.try_write().expect("mapping is already borrowed")
+    }
+
+    /// Exclusive access to the mapped records, fails if a clone of this wrapper holds any guard.
+    pub fn try_write(&mut self) -> Result<MmapRefMut<'_, [T]>, BorrowError> {
+        unsafe { MmapRefMut::new(self.ptr(), self.raw.borrow()) }
+    }
+
+    /// Writes modified pages back to the file, blocking until done.
+    pub fn flush(&self) -> Result<(), B::Error> {
+        self.raw.backend().flush()
+    }
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+
+    use crate::test_util::{backend_tests, zeroed};
+    use crate::{
+        FileHeader, LayoutError, MmapSafe, MmapSliceMutWrapper, MmapSliceWrapper, OpenOptions,
+        ResizableBackend,
+    };
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct Record {
+        id: u32,
+        value: u32,
+    }
+
+    backend_tests!(fill, sizes, short_file, with_header, len_overflow);
+
+    fn fill<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut rw = MmapSliceMutWrapper::<Record, B>::create(path, 10)?;
+        for (i, r) in rw.write().iter_mut().enumerate() {
+            r.id = i as u32;
+            r.value = 2 * i as u32;
+        }
+        rw.flush()?;
+
+        let ro = MmapSliceWrapper::<Record, B>::open(path)?;
+        assert_eq!(ro.len(), 10);
+        assert!(ro.get(10).is_none());
+        assert_eq!(ro.iter().map(|r| r.value).sum::<u32>(), 90);
+
+        // both handles look at the same pages
+        rw.write()[9].value = 0;
+        assert_eq!(ro.iter().map(|r| r.value).sum::<u32>(), 72);
+        Ok(())
+    }
+
+    fn sizes<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        // an empty file is an empty slice
+        zeroed::<B>(path, 0)?;
+        assert!(MmapSliceWrapper::<Record, B>::open(path)?.is_empty());
+
+        zeroed::<B>(path, 12)?;
+        let e = MmapSliceWrapper::<Record, B>::open(path).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            LayoutError::NotMultiple { len: 12, size: 8 }.to_string()
+        );
+
+        // `create` resizes an existing file both ways
+        zeroed::<B>(path, 4096)?;
+        assert_eq!(MmapSliceMutWrapper::<Record, B>::create(path, 3)?.len(), 3);
+        assert_eq!(MmapSliceWrapper::<Record, B>::open(path)?.len(), 3);
+        Ok(())
+    }
+
+    fn short_file<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        // cut off in the middle of the file header
+        zeroed::<B>(path, 8)?;
+        let e = MmapSliceWrapper::<Record, B>::open_with(path, OpenOptions::new().header(1))
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            LayoutError::TooSmall {
+                len: 8,
+                required: size_of::<FileHeader>()
+            }
+            .to_string()
+        );
+        Ok(())
+    }
+
+    fn with_header<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        // records start after the header and are counted without it
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(true).header(1);
+        MmapSliceMutWrapper::<Record, B>::open_with_len(path, &options, 4)?;
+        let ro = MmapSliceWrapper::<Record, B>::open_with(path, OpenOptions::new().header(1))?;
+        assert_eq!(ro.len(), 4);
+
+        // a new file grows to hold the header and no records
+        zeroed::<B>(path, 0)?;
+        let rw = MmapSliceMutWrapper::<Record, B>::open_with(
+            path,
+            OpenOptions::new().create(true).grow(true).header(1),
+        )?;
+        assert!(rw.is_empty());
+
+        // but not to where records needing a bigger alignment would start
+        zeroed::<B>(path, 0)?;
+        let e = MmapSliceMutWrapper::<u128, B>::open_with(
+            path,
+            OpenOptions::new().create(true).grow(true).header(1),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            e.to_string(),
+            LayoutError::TooSmall {
+                len: size_of::<FileHeader>(),
+                required: FileHeader::data_offset::<u128>()
+            }
+            .to_string()
+        );
+        Ok(())
+    }
+
+    fn len_overflow<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        // 2^61 records of 8 bytes wrap around to an empty file
+        let e = MmapSliceMutWrapper::<Record, B>::create(path, 1 << 61)
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), LayoutError::Overflow.to_string());
+        assert!(B::open(path, &OpenOptions::new(), false).is_err());
+        Ok(())
+    }
+}
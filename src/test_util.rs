@@ -0,0 +1,55 @@
+extern crate std;
+
+use std::string::String;
+
+use crate::{OpenOptions, ResizableBackend};
+
+/// Runs each test, a function generic over the backend that takes the path of a scratch file,
+/// once for every enabled backend.
+///
+/// The runs are named `<test>::memmap2` and `<test>::libc` and each starts without the file.
+macro_rules! backend_tests {
+    ($($test:ident),* $(,)?) => {$(
+        mod $test {
+            #[cfg(feature = "std")]
+            #[test]
+            fn memmap2() {
+                let path = $crate::test_util::scratch(module_path!(), "memmap2");
+                super::$test::<$crate::Memmap2Backend>(path.as_ref()).unwrap();
+            }
+
+            #[cfg(feature = "no_std")]
+            #[test]
+            fn libc() {
+                let path = $crate::test_util::scratch(module_path!(), "libc");
+                super::$test::<$crate::LibcBackend>(&$crate::test_util::c_path(path)).unwrap();
+            }
+        }
+    )*};
+}
+
+pub(crate) use backend_tests;
+
+/// A path under `/tmp` named after the test module and backend, removing any leftover file.
+pub(crate) fn scratch(module: &str, backend: &str) -> String {
+    let module = module
+        .split("::")
+        .skip(1)
+        .collect::<std::vec::Vec<_>>()
+        .join("-");
+    let path = std::format!("/tmp/mmap-wrapper-test-{module}-{backend}");
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Replaces the file at `path` with `len` zero bytes.
+pub(crate) fn zeroed<B: ResizableBackend>(path: &B::Path, len: usize) -> Result<(), B::Error> {
+    let file = B::open(path, OpenOptions::new().create(true).truncate(true), true)?;
+    B::set_file_len(&file, len)
+}
+
+/// `path` for the libc backend.
+#[cfg(feature = "no_std")]
+pub(crate) fn c_path(path: String) -> std::ffi::CString {
+    std::ffi::CString::new(path).unwrap()
+}
@@ -0,0 +1,366 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::slice;
+
+use crate::{DefaultBackend, LayoutError, MmapSafe, OpenOptions, ResizableBackend};
+
+/// Stored at the start of the file, followed by the items.
+#[repr(C)]
+struct VecHeader {
+    len: u64,
+}
+
+/// An append-able vector of `T` stored in a memory-mapped file.
+///
+/// The file starts with the length followed by `capacity` items, it grows geometrically
+/// like a `Vec` by resizing the file and remapping it. Every method that can remap takes
+/// `&mut self`, so no reference into the old mapping can outlive a resize.
+///
+/// Other handles to the same file see the items it mapped, items pushed past that show up
+/// once the handle grows or is reopened. Only one handle should push at a time.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapSafe, MmapVec};
+///
+/// #[derive(MmapSafe, Clone, Copy)]
+/// #[repr(C)]
+/// struct Event {
+///    id: u32,
+///    value: f32,
+/// }
+///
+/// let mut events = MmapVec::<Event>::create("/tmp/events-mmap-test.bin").unwrap();
+/// events.push(Event { id: 1, value: 0.5 }).unwrap();
+/// events.extend_from_slice(&[Event { id: 2, value: 1.5 }; 3]).unwrap();
+/// drop(events);
+///
+/// let events = MmapVec::<Event>::open("/tmp/events-mmap-test.bin").unwrap();
+/// assert_eq!(events.len(), 4);
+/// assert_eq!(events[3].id, 2);
+/// # }
+/// ```
+///
+/// Growing needs `&mut self`, so items cannot be borrowed across a push:
+/// ```rust,compile_fail
+/// use mmap_wrapper::MmapVec;
+///
+/// let mut numbers = MmapVec::<u64>::create("/tmp/numbers-mmap-test.bin").unwrap();
+/// numbers.push(1).unwrap();
+///
+/// let first = &numbers[0];
+/// numbers.push(2).unwrap();
+/// assert_eq!(*first, 1);
+/// ```
+pub struct MmapVec<T, B: ResizableBackend = DefaultBackend> {
+    file: B::File,
+    map: B,
+    cap: usize,
+    _inner: PhantomData<T>,
+}
+
+impl<T: MmapSafe, B: ResizableBackend> MmapVec<T, B> {
+    const DATA_OFFSET: usize = size_of::<VecHeader>().next_multiple_of(align_of::<T>());
+    const MIN_CAP: usize = if size_of::<T>() > 1024 { 1 } else { 8 };
+
+    /// Maps the file at `path`, creating it if necessary and dropping any existing items.
+    pub fn create(path: impl AsRef<B::Path>) -> Result<MmapVec<T, B>, B::Error> {
+        Self::open_with(path, OpenOptions::new().create(true).truncate(true))
+    }
+
+    /// Maps the existing file at `path`, keeping its items.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapVec<T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the file at `path`, opening it according to `options`.
+    ///
+    /// An empty file becomes an empty vector, [`OpenOptions::truncate`] empties an existing one
+    /// and [`OpenOptions::grow`] is implied. The vector always starts at the beginning of the
+    /// file, [`OpenOptions::offset`] is ignored.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapVec<T, B>, B::Error> {
+        const { assert!(size_of::<T>() != 0, "cannot store zero sized types") };
+
+        let file = B::open(path.as_ref(), options, true)?;
+        let mut file_len = B::file_len(&file)?;
+        if options.truncate || file_len < Self::DATA_OFFSET {
+            B::set_file_len(&file, Self::DATA_OFFSET)?;
+            file_len = Self::DATA_OFFSET;
+        }
+
+        let data_len = file_len - Self::DATA_OFFSET;
+        if !data_len.is_multiple_of(size_of::<T>()) {
+            return Err(LayoutError::NotMultiple {
+                len: data_len,
+                size: size_of::<T>(),
+            }
+            .into());
+        }
+
+        let map = B::map_file(&file, file_len)?;
+        LayoutError::check::<VecHeader>(map.as_ptr(), map.len())?;
+
+        let mut vec = MmapVec {
+            file,
+            map,
+            cap: data_len / size_of::<T>(),
+            _inner: PhantomData,
+        };
+
+        if options.truncate {
+            vec.set_len(0);
+        }
+        if vec.stored_len() > vec.cap {
+            return Err(LayoutError::TooSmall {
+                len: file_len,
+                required: vec
+                    .stored_len()
+                    .saturating_mul(size_of::<T>())
+                    .saturating_add(Self::DATA_OFFSET),
+            }
+            .into());
+        }
+
+        Ok(vec)
+    }
+
+    fn header(&self) -> *mut VecHeader {
+        self.map.as_ptr().cast()
+    }
+
+    fn data(&self) -> *mut T {
+        unsafe { self.map.as_ptr().add(Self::DATA_OFFSET).cast() }
+    }
+
+    fn set_len(&mut self, len: usize) {
+        unsafe { (*self.header()).len = len as u64 };
+    }
+
+    fn stored_len(&self) -> usize {
+        unsafe { (*self.header()).len as usize }
+    }
+
+    /// Number of items, at most [`MmapVec::capacity`].
+    pub fn len(&self) -> usize {
+        // another handle may have pushed past what this one mapped
+        self.stored_len().min(self.cap)
+    }
+
+    /// Returns `true` if there are no items.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of items the file can hold without growing.
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// The stored items.
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.data(), self.len()) }
+    }
+
+    /// The stored items, mutably.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.data(), self.len()) }
+    }
+
+    /// Makes room for at least `additional` more items, growing the file geometrically.
+    ///
+    /// Fails with [`LayoutError::Overflow`] if the items would take more than `isize::MAX` bytes.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), B::Error> {
+        // slices can't be any longer
+        let max_cap = (isize::MAX as usize - Self::DATA_OFFSET) / size_of::<T>();
+        let required = self
+            .len()
+            .checked_add(additional)
+            .filter(|&n| n <= max_cap)
+            .ok_or(LayoutError::Overflow)?;
+        if required <= self.cap {
+            return Ok(());
+        }
+
+        let cap = required
+            .max(self.cap.saturating_mul(2))
+            .max(Self::MIN_CAP)
+            .min(max_cap);
+        let mut file_len = cap
+            .checked_mul(size_of::<T>())
+            .and_then(|n| n.checked_add(Self::DATA_OFFSET))
+            .ok_or(LayoutError::Overflow)?;
+
+        // another handle may have grown the file already, shrinking it would cut off its items
+        let current = B::file_len(&self.file)?;
+        if current > file_len {
+            file_len = current;
+        } else {
+            B::set_file_len(&self.file, file_len)?;
+        }
+        self.map.remap(&self.file, file_len)?;
+        self.cap = (file_len - Self::DATA_OFFSET) / size_of::<T>();
+
+        Ok(())
+    }
+
+    /// Appends `value`, growing the file if it is full.
+    pub fn push(&mut self, value: T) -> Result<(), B::Error> {
+        self.reserve(1)?;
+
+        let len = self.len();
+        unsafe { self.data().add(len).write(value) };
+        // only count the item once it is written
+        self.set_len(len + 1);
+
+        Ok(())
+    }
+
+    /// Appends copies of every item of `values`, growing the file at most once.
+    pub fn extend_from_slice(&mut self, values: &[T]) -> Result<(), B::Error>
+    where
+        T: Copy,
+    {
+        self.reserve(values.len())?;
+
+        let len = self.len();
+        unsafe { ptr::copy_nonoverlapping(values.as_ptr(), self.data().add(len), values.len()) };
+        self.set_len(len + values.len());
+
+        Ok(())
+    }
+
+    /// Shortens the vector to `len` items, keeping the file size.
+    ///
+    /// Has no effect if `len` is greater than the current length.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            self.set_len(len);
+        }
+    }
+
+    /// Writes modified pages back to the file, blocking until done.
+    pub fn flush(&self) -> Result<(), B::Error> {
+        self.map.flush()
+    }
+}
+
+impl<T: MmapSafe, B: ResizableBackend> Deref for MmapVec<T, B> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: MmapSafe, B: ResizableBackend> DerefMut for MmapVec<T, B> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+
+    use crate::test_util::{backend_tests, zeroed};
+    use crate::{LayoutError, MmapMutWrapper, MmapVec, ResizableBackend};
+
+    backend_tests!(append, short_file, two_handles, overflow);
+
+    fn append<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut vec = MmapVec::<u64, B>::create(path)?;
+        for i in 0..1000 {
+            vec.push(i)?;
+        }
+        vec.extend_from_slice(&[1; 24])?;
+        assert_eq!(vec.len(), 1024);
+        assert!(vec.capacity() >= 1024);
+
+        vec.truncate(1000);
+        vec.push(0)?;
+        drop(vec);
+
+        let vec = MmapVec::<u64, B>::open(path)?;
+        assert_eq!(vec.len(), 1001);
+        assert_eq!(vec.iter().sum::<u64>(), 499500);
+        Ok(())
+    }
+
+    fn short_file<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        // too short for the length, starts out empty
+        zeroed::<B>(path, 3)?;
+        assert!(MmapVec::<u64, B>::open(path)?.is_empty());
+
+        // cut off in the middle of an item
+        zeroed::<B>(path, 8 + 12)?;
+        let e = MmapVec::<u64, B>::open(path).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            LayoutError::NotMultiple { len: 12, size: 8 }.to_string()
+        );
+
+        // a stored length past the end of the file
+        zeroed::<B>(path, 8 + 16)?;
+        *MmapMutWrapper::<u64, B>::open(path)?.write() = 3;
+        let e = MmapVec::<u64, B>::open(path).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            LayoutError::TooSmall {
+                len: 24,
+                required: 32
+            }
+            .to_string()
+        );
+        Ok(())
+    }
+
+    fn two_handles<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut first = MmapVec::<u64, B>::create(path)?;
+        first.push(1)?;
+        let mut second = MmapVec::<u64, B>::open(path)?;
+        assert_eq!(second.capacity(), first.capacity());
+
+        // grows the file well past what the second handle mapped
+        for i in 0..1000 {
+            first.push(i)?;
+        }
+        assert_eq!(second.len(), second.capacity());
+        assert_eq!(second[1..].iter().sum::<u64>(), (0..7).sum());
+
+        // growing the second handle picks up the file instead of shrinking it
+        second.reserve(1)?;
+        assert_eq!(second.capacity(), first.capacity());
+        assert_eq!(second.len(), 1001);
+        drop(first);
+        second.push(1000)?;
+
+        let vec = MmapVec::<u64, B>::open(path)?;
+        assert_eq!(vec.len(), 1002);
+        assert_eq!(vec[1..].iter().sum::<u64>(), (0..1001).sum());
+        Ok(())
+    }
+
+    fn overflow<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut vec = MmapVec::<[u64; 4], B>::create(path)?;
+        vec.push([1; 4])?;
+        for additional in [usize::MAX, usize::MAX / 32, isize::MAX as usize / 32] {
+            let e = vec.reserve(additional).err().unwrap();
+            assert_eq!(e.to_string(), LayoutError::Overflow.to_string());
+        }
+        assert_eq!(vec.len(), 1);
+        drop(vec);
+
+        // a stored length that overflows the file length
+        *MmapMutWrapper::<u64, B>::open(path)?.write() = u64::MAX;
+        assert!(MmapVec::<[u64; 4], B>::open(path).is_err());
+        Ok(())
+    }
+}
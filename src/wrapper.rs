@@ -0,0 +1,513 @@
+use crate::borrow::{BorrowError, MmapRef, MmapRefMut};
+use crate::header;
+use crate::migrate::{self, Migrate};
+use crate::shared::Shared;
+use crate::{DefaultBackend, LayoutError, MappingBackend, MmapSafe, OpenOptions};
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+/// A wrapper for a memory-mapped file with data of type `T`.
+///
+/// Clones share the same mapping, the last one to be dropped unmaps it.
+///
+/// # Safety
+///
+/// `T` must implement [`MmapSafe`] to ensure that the data is casted correctly.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapMutWrapper, MmapSafe, MmapWrapper};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct MyStruct {
+///    thing1: i64,
+///    thing2: f64,
+/// }
+///
+/// // creates the file, read-only wrappers only open existing ones
+/// let _ = MmapMutWrapper::<MyStruct>::create("/tmp/mystruct-mmap-test.bin").unwrap();
+///
+/// let m_wrapper = MmapWrapper::<MyStruct>::open("/tmp/mystruct-mmap-test.bin").unwrap();
+/// let mmap_backed_mystruct = m_wrapper.read();
+/// # }
+/// ```
+pub struct MmapWrapper<T, B: MappingBackend = DefaultBackend> {
+    raw: Shared<B>,
+    // where `T` starts, past the file header if there is one,
+    // and how many bytes from there this wrapper and its views may use
+    offset: usize,
+    len: usize,
+    _inner: PhantomData<T>,
+}
+
+/// A mutable wrapper for a memory-mapped file with data of type `T`.
+///
+/// Clones share the mapping and a borrow flag, so at most one of them can
+/// hold a [`MmapRefMut`] at any time.
+///
+/// # Safety
+///
+/// `T` must implement [`MmapSafe`] to ensure that the data is casted correctly.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(all(feature = "std", feature = "derive"))] {
+/// use mmap_wrapper::{MmapMutWrapper, MmapSafe};
+///
+/// #[derive(MmapSafe)]
+/// #[repr(C)]
+/// struct MyStruct {
+///    thing1: i64,
+///    thing2: f64,
+/// }
+///
+/// let mut m_wrapper = MmapMutWrapper::<MyStruct>::create("/tmp/mystruct-mmap-mut-test.bin").unwrap();
+/// let other = m_wrapper.clone();
+///
+/// let mut mmap_backed_mystruct = m_wrapper.write();
+/// mmap_backed_mystruct.thing1 = 1;
+///
+/// // a clone cannot read while the write guard is alive
+/// assert!(other.try_read().is_err());
+/// # }
+/// ```
+///
+/// Clones share `&T` between threads, so the wrapper is only `Send` if `T` is `Sync` too:
+/// ```rust,compile_fail
+/// use core::cell::Cell;
+/// use mmap_wrapper::{MmapMutWrapper, MmapSafe};
+///
+/// #[repr(transparent)]
+/// struct Counter(Cell<u64>);
+///
+/// unsafe impl MmapSafe for Counter {}
+///
+/// fn assert_send<T: Send>() {}
+/// assert_send::<MmapMutWrapper<Counter>>();
+/// ```
+pub struct MmapMutWrapper<T, B: MappingBackend = DefaultBackend> {
+    raw: Shared<B>,
+    // where `T` starts, past the file header if there is one,
+    // and how many bytes from there this wrapper and its views may use
+    offset: usize,
+    len: usize,
+    _inner: PhantomData<T>,
+}
+
+// Clones and views hand out `&T` on any thread and a write guard on one of them
+// moves `&mut T` across, same bounds as `Arc<RwLock<T>>`.
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Send for MmapWrapper<T, B> {}
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Sync for MmapWrapper<T, B> {}
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Send for MmapMutWrapper<T, B> {}
+unsafe impl<T: Send + Sync, B: MappingBackend + Send + Sync> Sync for MmapMutWrapper<T, B> {}
+
+impl<T, B: MappingBackend> Clone for MmapWrapper<T, B> {
+    fn clone(&self) -> Self {
+        MmapWrapper {
+            raw: self.raw.clone(),
+            offset: self.offset,
+            len: self.len,
+            _inner: PhantomData,
+        }
+    }
+}
+
+impl<T, B: MappingBackend> Clone for MmapMutWrapper<T, B> {
+    fn clone(&self) -> Self {
+        MmapMutWrapper {
+            raw: self.raw.clone(),
+            offset: self.offset,
+            len: self.len,
+            _inner: PhantomData,
+        }
+    }
+}
+
+/// Checks that a `U` fits `offset` bytes into the `len` bytes at `base`,
+/// returning how many bytes are left from there.
+fn sub_region<U>(base: *const u8, len: usize, offset: usize) -> Result<usize, LayoutError> {
+    // zero sized types fit anywhere, but not past the end
+    if offset > len {
+        return Err(LayoutError::TooSmall {
+            len,
+            required: offset.saturating_add(size_of::<U>()),
+        });
+    }
+
+    let left = len - offset;
+    LayoutError::check::<U>(base.wrapping_add(offset), left)?;
+    Ok(left)
+}
+
+impl<T: MmapSafe, B: MappingBackend> MmapWrapper<T, B> {
+    /// Wraps an existing mapping, checking that it is at least
+    /// `size_of::<T>()` bytes long and aligned for `T`.
+    pub fn from_backend(backend: B) -> Result<MmapWrapper<T, B>, B::Error> {
+        Self::from_parts(backend, 0)
+    }
+
+    fn from_parts(backend: B, offset: usize) -> Result<MmapWrapper<T, B>, B::Error> {
+        let len = backend.len().saturating_sub(offset);
+        LayoutError::check::<T>(backend.as_ptr().wrapping_add(offset), len)?;
+
+        Ok(MmapWrapper {
+            raw: Shared::new(backend)?,
+            offset,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    fn ptr(&self) -> *mut T {
+        unsafe { self.raw.backend().as_ptr().add(self.offset).cast::<T>() }
+    }
+
+    /// Maps the existing file at `path` read-only.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapWrapper<T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the `T` stored `offset` bytes into the existing file at `path` read-only.
+    ///
+    /// `offset` does not need to be page aligned, see [`OpenOptions::offset`].
+    pub fn open_at(
+        path: impl AsRef<B::Path>,
+        offset: usize,
+    ) -> Result<MmapWrapper<T, B>, B::Error> {
+        Self::open_with(path, OpenOptions::new().offset(offset))
+    }
+
+    /// Maps the file at `path` read-only, opening it according to `options`.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapWrapper<T, B>, B::Error> {
+        header::probe::<T, B>(path.as_ref(), options, false)?;
+        let backend = B::map(path.as_ref(), options, Some(options.map_len::<T>()), false)?;
+        let offset = header::init::<T, B>(&backend, options.header, false)?;
+        Self::from_parts(backend, offset)
+    }
+
+    /// A view of the `U` stored `offset` bytes past the start of this `T`.
+    ///
+    /// The view shares this mapping and keeps it alive, it may cover any bytes of the
+    /// mapping this wrapper can reach, not just those of `T`.
+    pub fn view<U: MmapSafe>(&self, offset: usize) -> Result<MmapWrapper<U, B>, LayoutError> {
+        let base = unsafe { self.raw.backend().as_ptr().add(self.offset) };
+        let len = sub_region::<U>(base, self.len, offset)?;
+
+        Ok(MmapWrapper {
+            raw: self.raw.clone(),
+            offset: self
+                .offset
+                .checked_add(offset)
+                .ok_or(LayoutError::Overflow)?,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    /// Splits the bytes this wrapper can reach at `mid` into a view of a `U` at the start
+    /// and a view of a `V` at `mid`, the `U` view cannot reach past `mid`.
+    #[allow(clippy::type_complexity)]
+    pub fn split_at<U: MmapSafe, V: MmapSafe>(
+        &self,
+        mid: usize,
+    ) -> Result<(MmapWrapper<U, B>, MmapWrapper<V, B>), LayoutError> {
+        let base = unsafe { self.raw.backend().as_ptr().add(self.offset) };
+        let first = sub_region::<U>(base, self.len.min(mid), 0)?;
+        let second = self.view::<V>(mid)?;
+
+        Ok((
+            MmapWrapper {
+                raw: self.raw.clone(),
+                offset: self.offset,
+                len: first,
+                _inner: PhantomData,
+            },
+            second,
+        ))
+    }
+
+    /// Returns a reference to the mapped `T` that cannot outlive this wrapper.
+    pub fn read(&self) -> &T {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl<T: MmapSafe, B: MappingBackend> MmapMutWrapper<T, B> {
+    /// Wraps an existing writable mapping, checking that it is at least
+    /// `size_of::<T>()` bytes long and aligned for `T`.
+    ///
+    /// Fails with [`LayoutError::ReadOnly`] for a read-only mapping.
+    pub fn from_backend(backend: B) -> Result<MmapMutWrapper<T, B>, B::Error> {
+        if !backend.writable() {
+            return Err(LayoutError::ReadOnly.into());
+        }
+        Self::from_parts(backend, 0)
+    }
+
+    fn from_parts(backend: B, offset: usize) -> Result<MmapMutWrapper<T, B>, B::Error> {
+        let len = backend.len().saturating_sub(offset);
+        LayoutError::check::<T>(backend.as_ptr().wrapping_add(offset), len)?;
+
+        Ok(MmapMutWrapper {
+            raw: Shared::new(backend)?,
+            offset,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    fn ptr(&self) -> *mut T {
+        unsafe { self.raw.backend().as_ptr().add(self.offset).cast::<T>() }
+    }
+
+    /// Maps the file at `path` read-write, creating it if necessary
+    /// and resizing it to exactly the size of `T`.
+    pub fn create(path: impl AsRef<B::Path>) -> Result<MmapMutWrapper<T, B>, B::Error> {
+        Self::open_with(path, OpenOptions::new().create(true).truncate(true))
+    }
+
+    /// Maps the existing file at `path` read-write without resizing it.
+    pub fn open(path: impl AsRef<B::Path>) -> Result<MmapMutWrapper<T, B>, B::Error> {
+        Self::open_with(path, &OpenOptions::new())
+    }
+
+    /// Maps the `T` stored `offset` bytes into the existing file at `path` read-write.
+    ///
+    /// `offset` does not need to be page aligned, see [`OpenOptions::offset`].
+    pub fn open_at(
+        path: impl AsRef<B::Path>,
+        offset: usize,
+    ) -> Result<MmapMutWrapper<T, B>, B::Error> {
+        Self::open_with(path, OpenOptions::new().offset(offset))
+    }
+
+    /// Maps the file at `path` read-write, opening it according to `options`.
+    pub fn open_with(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+    ) -> Result<MmapMutWrapper<T, B>, B::Error> {
+        let fresh = header::probe::<T, B>(path.as_ref(), options, true)?;
+        let backend = B::map(path.as_ref(), options, Some(options.map_len::<T>()), true)?;
+        let offset = header::init::<T, B>(&backend, options.header, fresh)?;
+        Self::from_parts(backend, offset)
+    }
+
+    /// Maps the file at `path` read-write like [`MmapMutWrapper::open_with`], first upgrading
+    /// older files with the matching steps of `migrations`.
+    ///
+    /// Needs [`OpenOptions::header`], the stored version picks the first step to run and each
+    /// step moves it up by one until the file is at the version `options` asks for.
+    /// Files left at a version without a step fail to open with [`HeaderError::Version`].
+    ///
+    /// Each step writes the upgraded file next to the old one, with its permissions and owner,
+    /// and renames it over it, a crash mid-migration leaves the last complete version. Processes
+    /// that already have the file mapped keep seeing the old contents.
+    ///
+    /// [`HeaderError::Version`]: crate::HeaderError::Version
+    pub fn open_migrated(
+        path: impl AsRef<B::Path>,
+        options: &OpenOptions,
+        migrations: &[&dyn Migrate],
+    ) -> Result<MmapMutWrapper<T, B>, B::Error> {
+        if let Some(to) = options.header {
+            migrate::run::<B>(path.as_ref(), options, to, migrations)?;
+        }
+        Self::open_with(path, options)
+    }
+
+    /// A view of the `U` stored `offset` bytes past the start of this `T`.
+    ///
+    /// The view shares this mapping and keeps it alive, it may cover any bytes of the
+    /// mapping this wrapper can reach, not just those of `T`.
+    ///
+    /// Views share the borrow flag of this wrapper, a write guard on any of them
+    /// blocks every other guard of the mapping.
+    pub fn view<U: MmapSafe>(&self, offset: usize) -> Result<MmapMutWrapper<U, B>, LayoutError> {
+        let base = unsafe { self.raw.backend().as_ptr().add(self.offset) };
+        let len = sub_region::<U>(base, self.len, offset)?;
+
+        Ok(MmapMutWrapper {
+            raw: self.raw.clone(),
+            offset: self
+                .offset
+                .checked_add(offset)
+                .ok_or(LayoutError::Overflow)?,
+            len,
+            _inner: PhantomData,
+        })
+    }
+
+    /// Splits the bytes this wrapper can reach at `mid` into a view of a `U` at the start
+    /// and a view of a `V` at `mid`, the `U` view cannot reach past `mid`.
+    #[allow(clippy::type_complexity)]
+    pub fn split_at<U: MmapSafe, V: MmapSafe>(
+        &self,
+        mid: usize,
+    ) -> Result<(MmapMutWrapper<U, B>, MmapMutWrapper<V, B>), LayoutError> {
+        let base = unsafe { self.raw.backend().as_ptr().add(self.offset) };
+        let first = sub_region::<U>(base, self.len.min(mid), 0)?;
+        let second = self.view::<V>(mid)?;
+
+        Ok((
+            MmapMutWrapper {
+                raw: self.raw.clone(),
+                offset: self.offset,
+                len: first,
+                _inner: PhantomData,
+            },
+            second,
+        ))
+    }
+
+    /// Shared access to the mapped `T`.
+    ///
+    /// Types that write to the mapping through a shared reference, like the locks, offer
+    /// those methods on the returned [`MmapRef`], see there.
+    ///
+    /// # Panics
+    /// Panics if a clone of this wrapper holds a write guard,
+    /// use [`MmapMutWrapper::try_read`] to handle this case.
+    pub fn read(&self) -> MmapRef<'_, T> {
+        self.try_read()
+            .expect("mapping is already mutably borrowed")
+    }
+
+    /// Shared access to the mapped `T`, fails if a clone of this wrapper holds a write guard.
+    pub fn try_read(&self) -> Result<MmapRef<'_, T>, BorrowError> {
+        unsafe { MmapRef::new(self.ptr(), self.raw.borrow()) }
+    }
+
+    /// Exclusive access to the mapped `T`.
+    ///
+    /// # Panics
+    /// Panics if a clone of this wrapper holds any guard,
+    /// use [`MmapMutWrapper::try_write`] to handle this case.
+    pub fn write(&mut self) -> MmapRefMut<'_, T> {
+        self.try_write().expect("mapping is already borrowed")
+    }
+
+    /// Exclusive access to the mapped `T`, fails if a clone of this wrapper holds any guard.
+    pub fn try_write(&mut self) -> Result<MmapRefMut<'_, T>, BorrowError> {
+        unsafe { MmapRefMut::new(self.ptr(), self.raw.borrow()) }
+    }
+
+    /// Writes modified pages back to the file, blocking until done.
+    pub fn flush(&self) -> Result<(), B::Error> {
+        self.raw.backend().flush()
+    }
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    extern crate std;
+
+    use crate::test_util::{backend_tests, zeroed};
+    use crate::{MmapMutWrapper, MmapSafe, MmapWrapper, ResizableBackend};
+
+    #[derive(MmapSafe)]
+    #[repr(C)]
+    struct Counter {
+        count: u64,
+    }
+
+    backend_tests!(bump, two_counters, short_file);
+
+    // written once against the trait, run for every enabled backend
+    fn bump<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        let mut rw = MmapMutWrapper::<Counter, B>::create(path)?;
+        rw.write().count += 1;
+        rw.flush()?;
+
+        let ro = MmapWrapper::<Counter, B>::open(path)?;
+        assert_eq!(ro.read().count, 1);
+
+        // a second writable handle sees the same count
+        let mut other = MmapMutWrapper::<Counter, B>::open(path)?;
+        other.write().count += 1;
+        assert_eq!(ro.read().count, 2);
+        Ok(())
+    }
+
+    // two counters in one file, the second one is not page aligned
+    fn two_counters<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        zeroed::<B>(path, 8192)?;
+        let mut first = MmapMutWrapper::<Counter, B>::open_at(path, 0)?;
+        let mut second = MmapMutWrapper::<Counter, B>::open_at(path, 5000)?;
+        first.write().count = 1;
+        second.write().count = 2;
+
+        let first = MmapWrapper::<Counter, B>::open_at(path, 0)?;
+        let second = MmapWrapper::<Counter, B>::open_at(path, 5000)?;
+        assert_eq!((first.read().count, second.read().count), (1, 2));
+
+        let whole = MmapWrapper::<[u64; 1024], B>::open(path)?;
+        assert_eq!(whole.read()[5000 / 8], 2);
+        Ok(())
+    }
+
+    fn short_file<B: ResizableBackend>(path: &B::Path) -> Result<(), B::Error> {
+        zeroed::<B>(path, 8192)?;
+        // runs off the end of the file
+        assert!(MmapWrapper::<Counter, B>::open_at(path, 8190).is_err());
+        assert!(MmapMutWrapper::<Counter, B>::open_at(path, usize::MAX).is_err());
+
+        zeroed::<B>(path, 4)?;
+        assert!(MmapWrapper::<Counter, B>::open(path).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn views_share_mapping() {
+        use crate::LayoutError;
+
+        let path = "/tmp/mmap-wrapper-test-views";
+        let _ = std::fs::remove_file(path);
+
+        // a 4 KiB region table: a counter, then a block of records at 64
+        let table = MmapMutWrapper::<[u64; 512]>::create(path).unwrap();
+        let (mut stats, mut records) = table.split_at::<Counter, [u64; 8]>(64).unwrap();
+        drop(table);
+
+        stats.write().count = 3;
+        records.write()[7] = 9;
+        assert!(records.try_write().is_ok());
+
+        let ro = MmapWrapper::<[u64; 512]>::open(path).unwrap();
+        assert_eq!(ro.read()[0], 3);
+        assert_eq!(ro.read()[8 + 7], 9);
+
+        // views can look at any part of the mapping but not past it
+        assert_eq!(ro.view::<u64>(4088).unwrap().read(), &0);
+        assert_eq!(
+            ro.view::<u64>(4090).err(),
+            Some(LayoutError::TooSmall {
+                len: 6,
+                required: 8
+            })
+        );
+        assert!(matches!(
+            ro.view::<u64>(4),
+            Err(LayoutError::Misaligned { .. })
+        ));
+        // the first half of a split stops at `mid`
+        assert!(ro.split_at::<[u64; 2], u64>(8).is_err());
+
+        // zero sized views still have to start inside the mapping
+        assert!(ro.view::<()>(4096).is_ok());
+        assert_eq!(
+            ro.view::<()>(usize::MAX).err(),
+            Some(LayoutError::TooSmall {
+                len: 4096,
+                required: usize::MAX
+            })
+        );
+        assert!(ro.view::<[u64; 512]>(0).unwrap().view::<()>(4097).is_err());
+        assert!(ro.split_at::<(), ()>(usize::MAX).is_err());
+    }
+}